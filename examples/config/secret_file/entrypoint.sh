@@ -0,0 +1,3 @@
+#!/bin/sh
+echo "$(cat "$SECRET_FILE")"
+printenv my.secret
@@ -0,0 +1,2 @@
+#!/bin/bash
+echo "${TIDPLOY_TEST_INHERITED:-unset} ${TIDPLOY_TEST_LEAKED:-unset}"
\ No newline at end of file
@@ -0,0 +1,3 @@
+#!/bin/bash
+echo "Failing..."
+exit 3
\ No newline at end of file
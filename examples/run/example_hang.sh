@@ -0,0 +1,5 @@
+#!/bin/bash
+echo "Pulling..."
+# The background process keeps our stdout open, so it must be killed as well
+sleep 30 &
+wait
\ No newline at end of file
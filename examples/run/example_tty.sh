@@ -0,0 +1,6 @@
+#!/bin/bash
+if [ -t 1 ]; then
+    echo "In a terminal!"
+else
+    echo "Not in a terminal!"
+fi
\ No newline at end of file
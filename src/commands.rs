@@ -92,7 +92,7 @@ enum Commands {
         archive: Option<String>,
     },
     /// Next version
-    Next(NextSub),
+    Next(Box<NextSub>),
 }
 
 #[derive(ThisError, Debug)]
@@ -361,6 +361,6 @@ pub fn run_cli() -> Result<ExitCode, Report> {
 
             Ok(ExitCode::from(code))
         }
-        Commands::Next(next_sub) => match_command(next_sub, cmd),
+        Commands::Next(next_sub) => match_command(*next_sub, cmd),
     }
 }
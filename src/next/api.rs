@@ -1,13 +1,35 @@
-use super::run::{run_command_input as inner_run_command, RunOptions};
+use super::cache::{cache_repos, prune_cache, PrunePolicy};
+use super::history::{history_command as inner_history_command, HistoryFilter};
+use super::lockfile::{update_command as inner_update_command, LockMode};
+use super::logs::logs_command as inner_logs_command;
+use super::meta::read_meta;
+use super::resolve::ProcessArguments;
+use super::run::{
+    rollback_command as inner_rollback_command, run_command_input as inner_run_command, RunOptions,
+    SuperviseOptions,
+};
 use super::secrets::secret_command as inner_secret_command;
 use super::state::StateOptions;
+use super::watch::{watch_command as inner_watch_command, WatchOptions};
 
-use camino::Utf8PathBuf;
+use std::{
+    mem,
+    time::{Duration, SystemTime},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
 use color_eyre::eyre::Report;
 use thiserror::Error as ThisError;
 
-pub use super::process::EntrypointOut;
-pub use super::state::{AddressIn, GitAddressIn, LocalAddressIn};
+pub use super::cache::{CachedCheckout, CachedRepo};
+pub use super::history::HistoryEntry;
+pub use super::lock::LockWait;
+pub use super::lockfile::{GitPin, LOCK_FILENAME};
+pub use super::meta::{CheckoutMeta, RepoMeta, StoreMeta, META_FILENAME, META_VERSION};
+pub use super::process::{AttemptOut, EntrypointOut};
+pub use super::run::RestartPolicy;
+pub use super::state::{AddressIn, GitAddressIn, LocalAddressIn, ResolvedAddress};
+pub use super::watch::WatchedDeploy;
 pub use crate::state::StateContext;
 
 /// These represent global arguments that correspond to global args of the CLI (i.e. valid for all
@@ -30,6 +52,13 @@ pub struct GlobalArguments {
     pub git_infer: bool,
     pub store_dir: Option<Utf8PathBuf>,
     pub address: Option<AddressIn>,
+    /// Only use repositories and checkouts that are already in the store dir, never contacting a Git
+    /// remote. Also enabled by setting the `TIDPLOY_OFFLINE` environment variable.
+    pub offline: bool,
+    /// Fail if the lock file next to the root config does not pin exactly the Git addresses in the configs,
+    /// instead of resolving the ones that are not pinned. For a root Git address, its pin in the store must
+    /// match as well.
+    pub locked: bool,
 }
 
 impl GlobalArguments {
@@ -61,6 +90,13 @@ impl From<GlobalArguments> for StateOptions {
 
         Self {
             store_dir: value.store_dir.unwrap_or(default.store_dir),
+            offline: value.offline || default.offline,
+            lock_mode: if value.locked {
+                LockMode::Locked
+            } else {
+                default.lock_mode
+            },
+            root_pin: None,
         }
     }
 }
@@ -75,6 +111,81 @@ pub struct RunArguments {
     pub variables: Vec<String>,
     pub service: Option<String>,
     pub input_bytes: Option<Vec<u8>>,
+    /// Seconds after which the entrypoint and all processes it started are killed.
+    pub timeout: Option<u64>,
+    pub retries: Option<u32>,
+    /// Seconds to wait between attempts.
+    pub retry_delay: Option<u64>,
+    /// Exit codes to retry on. If empty, any failure is retried.
+    pub retry_on: Vec<i32>,
+    /// Start the entrypoint from an empty environment, with only the variables in `inherit` copied over. If
+    /// None, the config decides. Giving `inherit` while `clean_env` is not set anywhere is an error.
+    pub clean_env: Option<bool>,
+    pub inherit: Vec<String>,
+    /// Mask the values of loaded secrets in the output of the entrypoint.
+    pub redact: bool,
+    /// Run the entrypoint in a pseudo-terminal. Note that its output will then use "\r\n" line endings.
+    pub pty: bool,
+    /// Also write the output of the entrypoint to a log file in the store dir, see [logs_command].
+    pub log: bool,
+    /// Number of log files kept for this address.
+    pub log_max_files: Option<usize>,
+    /// Size in bytes after which a log file is continued in a new one.
+    pub log_max_size: Option<u64>,
+    /// Keep the entrypoint running instead of running it once.
+    pub supervise: Option<SuperviseArguments>,
+    /// If the entrypoint succeeds, record the checkout as the current release so that [rollback_command]
+    /// can later return to it.
+    pub release: bool,
+    /// Hold the deploy lock of the address while running, see [LockWait].
+    pub lock: Option<LockWait>,
+}
+
+/// Determines how a supervised entrypoint is restarted. Instantiate using [SuperviseArguments::new] and then
+/// set any of the optional fields, which are all in seconds except for `crash_limit`.
+#[non_exhaustive]
+#[derive(Clone)]
+pub struct SuperviseArguments {
+    pub policy: RestartPolicy,
+    pub backoff: Option<u64>,
+    pub backoff_max: Option<u64>,
+    pub crash_limit: Option<u32>,
+    pub crash_window: Option<u64>,
+}
+
+impl SuperviseArguments {
+    pub fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            backoff: None,
+            backoff_max: None,
+            crash_limit: None,
+            crash_window: None,
+        }
+    }
+}
+
+impl From<SuperviseArguments> for SuperviseOptions {
+    fn from(value: SuperviseArguments) -> Self {
+        let default = Self::new(value.policy);
+
+        Self {
+            policy: value.policy,
+            backoff: value
+                .backoff
+                .map(Duration::from_secs)
+                .unwrap_or(default.backoff),
+            backoff_max: value
+                .backoff_max
+                .map(Duration::from_secs)
+                .unwrap_or(default.backoff_max),
+            crash_limit: value.crash_limit.unwrap_or(default.crash_limit),
+            crash_window: value
+                .crash_window
+                .map(Duration::from_secs)
+                .unwrap_or(default.crash_window),
+        }
+    }
 }
 
 /// Simple wrapper error that displays the inner `eyre` [Report]. However, it is not directly accessible. Do
@@ -86,21 +197,47 @@ pub struct CommandError {
     source: Report,
 }
 
+/// Ignores the executable, execution path and variables, which are passed separately.
+impl From<RunArguments> for RunOptions {
+    fn from(args: RunArguments) -> Self {
+        Self {
+            service: args.service,
+            input_bytes: args.input_bytes,
+            process_args: ProcessArguments {
+                timeout: args.timeout,
+                retries: args.retries,
+                retry_delay: args.retry_delay,
+                retry_on: (!args.retry_on.is_empty()).then_some(args.retry_on),
+                clean_env: args.clean_env,
+                inherit: (!args.inherit.is_empty()).then_some(args.inherit),
+                redact: args.redact.then_some(true),
+                pty: args.pty.then_some(true),
+                log: args.log.then_some(true),
+                log_max_files: args.log_max_files,
+                log_max_size: args.log_max_size,
+            },
+            supervise: args.supervise.map(Into::into),
+            release: args.release,
+            lock: args.lock,
+        }
+    }
+}
+
 pub fn run_command(
     global_args: GlobalArguments,
-    args: RunArguments,
+    mut args: RunArguments,
 ) -> Result<EntrypointOut, CommandError> {
+    let executable = args.executable.take();
+    let execution_path = args.execution_path.take();
+    let variables = mem::take(&mut args.variables);
     inner_run_command(
         global_args.run_in(),
         global_args.git_infer,
         Some(global_args.into()),
-        RunOptions {
-            service: args.service,
-            input_bytes: args.input_bytes,
-        },
-        args.executable,
-        args.execution_path,
-        args.variables,
+        args.into(),
+        executable,
+        execution_path,
+        variables,
     )
     .map_err(|e| CommandError {
         msg: "An error occurred in the inner application layer.".to_owned(),
@@ -114,6 +251,9 @@ pub struct SecretArguments {
     pub key: String,
     pub service: Option<String>,
     pub prompt: Option<String>,
+    /// Scope the secret to the root config instead of the address it points to, as is done for the token and
+    /// SSH key of a Git address.
+    pub no_follow: bool,
 }
 
 pub fn secret_command(
@@ -127,9 +267,216 @@ pub fn secret_command(
         args.service,
         args.key,
         args.prompt,
+        !args.no_follow,
+    )
+    .map_err(|e| CommandError {
+        msg: "An error occurred in the inner application layer.".to_owned(),
+        source: e,
+    })
+}
+
+#[non_exhaustive]
+#[derive(Default)]
+pub struct LogsArguments {
+    /// Only return this many lines from the end of the log.
+    pub lines: Option<usize>,
+}
+
+/// Returns the logged output of the latest run of the address, or None if it was never run with logging
+/// enabled.
+pub fn logs_command(
+    global_args: GlobalArguments,
+    args: LogsArguments,
+) -> Result<Option<String>, CommandError> {
+    inner_logs_command(
+        global_args.run_in(),
+        global_args.git_infer,
+        Some(global_args.into()),
+        args.lines,
+    )
+    .map_err(|e| CommandError {
+        msg: "An error occurred in the inner application layer.".to_owned(),
+        source: e,
+    })
+}
+
+/// Filters for [history_command]. All of them are optional, see [GlobalArguments] for details on how to
+/// instantiate.
+#[non_exhaustive]
+#[derive(Default)]
+pub struct HistoryArguments {
+    pub name: Option<String>,
+    pub state_path: Option<String>,
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+    /// Only return runs that did not succeed.
+    pub failed: bool,
+    /// Only return the most recent this many runs.
+    pub limit: Option<usize>,
+}
+
+/// Returns the recorded runs in the store dir that match the filters, from oldest to newest.
+pub fn history_command(
+    global_args: GlobalArguments,
+    args: HistoryArguments,
+) -> Result<Vec<HistoryEntry>, CommandError> {
+    let filter = HistoryFilter {
+        name: args.name,
+        state_path: args.state_path,
+        since: args.since,
+        until: args.until,
+        failed: args.failed,
+        limit: args.limit,
+    };
+
+    inner_history_command(Some(global_args.into()), filter).map_err(|e| CommandError {
+        msg: "An error occurred in the inner application layer.".to_owned(),
+        source: e,
+    })
+}
+
+/// Runs the release before the current one again (see [RunArguments::release]), with the arguments it was
+/// originally run with. If it succeeds, it becomes the current release.
+pub fn rollback_command(global_args: GlobalArguments) -> Result<EntrypointOut, CommandError> {
+    inner_rollback_command(
+        global_args.run_in(),
+        global_args.git_infer,
+        Some(global_args.into()),
     )
     .map_err(|e| CommandError {
         msg: "An error occurred in the inner application layer.".to_owned(),
         source: e,
     })
 }
+
+/// Determines how [watch_command] checks for new commits. The durations are in seconds.
+#[non_exhaustive]
+#[derive(Default)]
+pub struct WatchArguments {
+    pub interval: Option<u64>,
+    pub debounce: Option<u64>,
+    pub min_interval: Option<u64>,
+    /// Stop watching after this many deploys. If None, it never returns unless it fails.
+    pub max_deploys: Option<usize>,
+}
+
+/// Keeps checking what the ref of the Git address points to and runs it with `run_args` every time it moves,
+/// recording each run in the history. Each deploy is passed to `on_deploy`. Returns once
+/// [WatchArguments::max_deploys] is reached.
+pub fn watch_command(
+    global_args: GlobalArguments,
+    mut run_args: RunArguments,
+    args: WatchArguments,
+    on_deploy: impl FnMut(WatchedDeploy),
+) -> Result<(), CommandError> {
+    let default = WatchOptions::default();
+    let options = WatchOptions {
+        interval: args
+            .interval
+            .map(Duration::from_secs)
+            .unwrap_or(default.interval),
+        debounce: args
+            .debounce
+            .map(Duration::from_secs)
+            .unwrap_or(default.debounce),
+        min_interval: args
+            .min_interval
+            .map(Duration::from_secs)
+            .unwrap_or(default.min_interval),
+        max_deploys: args.max_deploys,
+    };
+    let git_infer = global_args.git_infer;
+    let executable = run_args.executable.take();
+    let execution_path = run_args.execution_path.take();
+    let variables = mem::take(&mut run_args.variables);
+    let run_options = RunOptions::from(run_args);
+
+    inner_watch_command(
+        global_args.run_in(),
+        git_infer,
+        Some(global_args.into()),
+        options,
+        |addr_in, state_options| {
+            inner_run_command(
+                addr_in,
+                git_infer,
+                Some(state_options),
+                run_options.clone(),
+                executable.clone(),
+                execution_path.clone(),
+                variables.clone(),
+            )
+        },
+        on_deploy,
+    )
+    .map_err(|e| CommandError {
+        msg: "An error occurred in the inner application layer.".to_owned(),
+        source: e,
+    })
+}
+
+/// Resolves the Git addresses in the configs again, ignoring the lock file next to the root config, and
+/// pins them in it to the commits they resolve to now. If the address is a Git address, its commit is pinned in
+/// the store instead. Returns the new pins.
+pub fn update_command(global_args: GlobalArguments) -> Result<Vec<GitPin>, CommandError> {
+    inner_update_command(
+        global_args.run_in(),
+        global_args.git_infer,
+        Some(global_args.into()),
+    )
+    .map_err(|e| CommandError {
+        msg: "An error occurred in the inner application layer.".to_owned(),
+        source: e,
+    })
+}
+
+/// Lists the repositories in the store dir, together with their checkouts.
+pub fn cache_list_command(global_args: GlobalArguments) -> Result<Vec<CachedRepo>, CommandError> {
+    cache_repos(Some(global_args.into())).map_err(|e| CommandError {
+        msg: "An error occurred in the inner application layer.".to_owned(),
+        source: e,
+    })
+}
+
+/// Which checkouts [cache_prune_command] removes. At least one of `keep` and `older_than` must be set, see
+/// [GlobalArguments] for details on how to instantiate.
+#[non_exhaustive]
+#[derive(Default)]
+pub struct PruneArguments {
+    /// Keep this many of the newest checkouts of every repository.
+    pub keep: Option<usize>,
+    /// Only remove checkouts created longer ago than this.
+    pub older_than: Option<Duration>,
+    /// Only prune checkouts of this repository (name or directory name).
+    pub repo: Option<String>,
+    /// Only return what would be removed.
+    pub dry_run: bool,
+}
+
+/// Removes old checkouts from the store dir, returning the ones that were (or would be) removed. Checkouts
+/// of a release, or that are in use by a run, are never removed.
+pub fn cache_prune_command(
+    global_args: GlobalArguments,
+    args: PruneArguments,
+) -> Result<Vec<CachedCheckout>, CommandError> {
+    let policy = PrunePolicy {
+        keep: args.keep,
+        older_than: args.older_than,
+        repo: args.repo,
+        dry_run: args.dry_run,
+    };
+
+    prune_cache(Some(global_args.into()), policy).map_err(|e| CommandError {
+        msg: "An error occurred in the inner application layer.".to_owned(),
+        source: e,
+    })
+}
+
+/// Reads the metadata of a repository clone or checkout in the store, which is None for directories that
+/// are not (yet) complete.
+pub fn read_store_meta(dir: &Utf8Path) -> Result<Option<StoreMeta>, CommandError> {
+    read_meta(dir).map_err(|e| CommandError {
+        msg: format!("Failed to read metadata in {:?}.", dir),
+        source: e.into(),
+    })
+}
@@ -0,0 +1,333 @@
+use std::{
+    cmp::Reverse,
+    fs, io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{eyre, Context, Report};
+use tracing::debug;
+
+use super::{
+    lock::{lock_file_shared, remove_lock_file, sibling_lock_path, try_lock_file, FileLock},
+    meta::{read_meta, CheckoutMeta, RepoMeta, StoreMeta},
+    release::release_checkouts,
+    state::StateOptions,
+};
+
+/// A repository clone in the store, named `{name}_{hash}`, with all commits that were checked out from it.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct CachedRepo {
+    pub dir_name: String,
+    pub path: Utf8PathBuf,
+    pub meta: RepoMeta,
+    /// From newest to oldest.
+    pub checkouts: Vec<CachedCheckout>,
+}
+
+/// A checkout of a commit in the store, at `c/{repo dir name}/{short commit}/{paths hash}`.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct CachedCheckout {
+    pub path: Utf8PathBuf,
+    pub meta: CheckoutMeta,
+    pub created: SystemTime,
+    /// Whether this is the checkout of a release of some address, which `rollback` can return to.
+    pub release: bool,
+}
+
+fn subdirs(dir: &Utf8Path) -> io::Result<Vec<Utf8PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut dirs = Vec::new();
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.into_path());
+        }
+    }
+    dirs.sort();
+
+    Ok(dirs)
+}
+
+fn list_checkouts(
+    store_dir: &Utf8Path,
+    dir_name: &str,
+    releases: &[Utf8PathBuf],
+) -> io::Result<Vec<CachedCheckout>> {
+    let mut checkouts = Vec::new();
+    for commit_dir in subdirs(&store_dir.join("c").join(dir_name))? {
+        for path in subdirs(&commit_dir)? {
+            // Directories without metadata are incomplete and will be replaced when they are next used
+            let Some(StoreMeta::Checkout(meta)) = read_meta(&path)? else {
+                continue;
+            };
+            checkouts.push(CachedCheckout {
+                release: releases.iter().any(|c| c.starts_with(&path)),
+                created: meta.created_time().unwrap_or(UNIX_EPOCH),
+                meta,
+                path,
+            });
+        }
+    }
+    // Newest first
+    checkouts.sort_by_key(|c| Reverse(c.created));
+
+    Ok(checkouts)
+}
+
+/// All repositories in the store, with their checkouts from newest to oldest.
+pub(crate) fn list_cache(store_dir: &Utf8Path) -> io::Result<Vec<CachedRepo>> {
+    let releases = release_checkouts(store_dir)?;
+    let mut repos = Vec::new();
+    for path in subdirs(store_dir)? {
+        let Some(dir_name) = path.file_name().map(ToOwned::to_owned) else {
+            continue;
+        };
+        let Some(StoreMeta::Repo(meta)) = read_meta(&path)? else {
+            continue;
+        };
+        repos.push(CachedRepo {
+            checkouts: list_checkouts(store_dir, &dir_name, &releases)?,
+            meta,
+            dir_name,
+            path,
+        });
+    }
+
+    Ok(repos)
+}
+
+/// The checkout in the store that contains `dir`, or None if it is not inside one.
+pub(crate) fn checkout_root(dir: &Utf8Path) -> Option<&Utf8Path> {
+    dir.ancestors()
+        .find(|path| matches!(read_meta(path), Ok(Some(StoreMeta::Checkout(_)))))
+}
+
+/// Takes a shared lock on the checkout in the store that contains `dir`, which keeps it from being pruned.
+/// Returns None if `dir` is not inside a checkout (anymore).
+pub(crate) fn lock_checkout(dir: &Utf8Path) -> Result<Option<FileLock>, Report> {
+    let Some(path) = checkout_root(dir) else {
+        return Ok(None);
+    };
+    let lock = lock_file_shared(&sibling_lock_path(path)).wrap_err("Failed to lock checkout.")?;
+    // It might have been pruned while waiting for the lock
+    Ok(path.exists().then_some(lock))
+}
+
+/// Total size in bytes of all files in `dir`, not following symlinks.
+pub(crate) fn dir_size(dir: &Utf8Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        let metadata = entry.path().symlink_metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+
+    Ok(size)
+}
+
+/// Finds a repository by its name (if that is unique) or its directory name.
+pub(crate) fn find_repo(repos: Vec<CachedRepo>, repo: &str) -> Result<CachedRepo, Report> {
+    let mut matching: Vec<CachedRepo> = repos
+        .into_iter()
+        .filter(|r| r.dir_name == repo || r.meta.name == repo)
+        .collect();
+    match matching.len() {
+        0 => Err(eyre!("No repository named {} in the store!", repo)),
+        1 => Ok(matching.remove(0)),
+        _ => Err(eyre!(
+            "Multiple repositories are named {}, use the directory name instead: {}",
+            repo,
+            matching
+                .iter()
+                .map(|r| r.dir_name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+pub(crate) fn cache_repos(state_options: Option<StateOptions>) -> Result<Vec<CachedRepo>, Report> {
+    let store_dir = state_options.unwrap_or_default().store_dir;
+    debug!("Reading cache in {:?}", store_dir);
+
+    list_cache(&store_dir).wrap_err("Failed to read the store directory.")
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct PrunePolicy {
+    /// Keep this many of the newest checkouts of every repository. Checkouts of the same commit with different
+    /// paths are counted separately.
+    pub(crate) keep: Option<usize>,
+    /// Only remove checkouts created longer ago than this.
+    pub(crate) older_than: Option<Duration>,
+    /// Only prune checkouts of this repository.
+    pub(crate) repo: Option<String>,
+    /// Only report what would be removed.
+    pub(crate) dry_run: bool,
+}
+
+/// Removes checkouts according to the policy, returning the ones that were (or with `dry_run`, would be)
+/// removed. If both `keep` and `older_than` are set, a checkout must be outside the newest `keep` and be
+/// older than `older_than` to be removed. Checkouts of a release, or that are in use by a run (see
+/// [lock_checkout]), are never removed. Note that `keep` counts checkouts, and a commit is checked out again
+/// for every different set of paths.
+pub(crate) fn prune_cache(
+    state_options: Option<StateOptions>,
+    policy: PrunePolicy,
+) -> Result<Vec<CachedCheckout>, Report> {
+    if policy.keep.is_none() && policy.older_than.is_none() {
+        return Err(eyre!("Give at least one of keep or older than to prune!"));
+    }
+    let repos = cache_repos(state_options)?;
+    let repos = match &policy.repo {
+        Some(repo) => vec![find_repo(repos, repo)?],
+        None => repos,
+    };
+    let now = SystemTime::now();
+
+    let mut removed = Vec::new();
+    for repo in repos {
+        for (i, checkout) in repo.checkouts.into_iter().enumerate() {
+            let beyond_keep = policy.keep.is_none_or(|keep| i >= keep);
+            let old = policy.older_than.is_none_or(|older_than| {
+                now.duration_since(checkout.created)
+                    .is_ok_and(|age| age > older_than)
+            });
+            if !beyond_keep || !old || checkout.release {
+                continue;
+            }
+
+            if !policy.dry_run {
+                // Deploys hold a shared lock on their checkouts until the entrypoint exits
+                let lock_path = sibling_lock_path(&checkout.path);
+                let Some(lock) = try_lock_file(&lock_path).wrap_err("Failed to open lock.")? else {
+                    debug!("Checkout {:?} is in use, not removing it.", checkout.path);
+                    continue;
+                };
+                debug!("Removing checkout {:?}", checkout.path);
+                fs::remove_dir_all(&checkout.path)
+                    .wrap_err_with(|| format!("Failed to remove checkout {:?}.", checkout.path))?;
+                remove_lock_file(lock).wrap_err("Failed to remove lock.")?;
+                // Only succeeds once no checkouts of the commit (or their lock files) are left
+                if let Some(commit_dir) = checkout.path.parent() {
+                    let _ = fs::remove_dir(commit_dir);
+                }
+            }
+            removed.push(checkout);
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        time::{Duration, SystemTime},
+    };
+
+    use camino::{Utf8Path, Utf8PathBuf};
+
+    use super::{list_cache, lock_checkout, prune_cache, PrunePolicy};
+    use crate::next::{
+        meta::{write_meta, CheckoutMeta, RepoMeta, StoreMeta},
+        state::StateOptions,
+    };
+
+    fn fake_checkout(store_dir: &Utf8Path, commit: &str, age_days: u64) {
+        let path = store_dir.join("c/repo_abc").join(commit).join("paths");
+        fs::create_dir_all(&path).unwrap();
+        let mut meta = CheckoutMeta::new(
+            "https://example.com/repo".to_owned(),
+            "repo".to_owned(),
+            "main".to_owned(),
+            commit.to_owned(),
+            vec![String::new()],
+        );
+        let created = SystemTime::now() - Duration::from_secs(age_days * 24 * 60 * 60);
+        meta.created = humantime::format_rfc3339_seconds(created).to_string();
+        write_meta(&path, &StoreMeta::Checkout(meta)).unwrap();
+    }
+
+    #[test]
+    fn prune_keep_older_than() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_dir = Utf8PathBuf::from_path_buf(dir.path().to_owned()).unwrap();
+        let repo_dir = store_dir.join("repo_abc");
+        fs::create_dir_all(&repo_dir).unwrap();
+        let meta = RepoMeta::new("https://example.com/repo".to_owned(), "repo".to_owned());
+        write_meta(&repo_dir, &StoreMeta::Repo(meta)).unwrap();
+        for (commit, age) in [("c1", 30), ("c2", 20), ("c3", 10), ("c4", 1)] {
+            fake_checkout(&store_dir, commit, age);
+        }
+        // Written by older versions, before the metadata was versioned
+        let legacy = store_dir.join("c/repo_abc/c0/paths");
+        fs::create_dir_all(&legacy).unwrap();
+        fs::write(
+            legacy.join("tidploy_deploy_meta_c0_paths"),
+            "commit:c0\npaths:",
+        )
+        .unwrap();
+
+        let options = || {
+            Some(StateOptions {
+                store_dir: store_dir.clone(),
+                offline: false,
+                lock_mode: Default::default(),
+                root_pin: None,
+            })
+        };
+        let repos = list_cache(&store_dir).unwrap();
+        assert_eq!(1, repos.len());
+        assert_eq!("repo", repos[0].meta.name);
+        let commits: Vec<&str> = repos[0]
+            .checkouts
+            .iter()
+            .map(|c| c.meta.commit.as_str())
+            .collect();
+        assert_eq!(vec!["c0", "c4", "c3", "c2", "c1"], commits);
+        assert_eq!(None, repos[0].checkouts[0].meta.git_ref);
+        assert_eq!(Some("main"), repos[0].checkouts[1].meta.git_ref.as_deref());
+
+        // Keeps the newest 3, but c2 is not old enough
+        let policy = PrunePolicy {
+            keep: Some(3),
+            older_than: Some(Duration::from_secs(25 * 24 * 60 * 60)),
+            ..Default::default()
+        };
+        let removed = prune_cache(options(), policy).unwrap();
+        assert_eq!(1, removed.len());
+        assert_eq!("c1", removed[0].meta.commit);
+        // Nothing is left of the commit, not even the lock file
+        assert!(!store_dir.join("c/repo_abc/c1").exists());
+
+        // A checkout that is in use is not removed
+        let in_use = lock_checkout(&store_dir.join("c/repo_abc/c2/paths/sub"))
+            .unwrap()
+            .unwrap();
+        let policy = PrunePolicy {
+            keep: Some(3),
+            ..Default::default()
+        };
+        assert!(prune_cache(options(), policy).unwrap().is_empty());
+        drop(in_use);
+
+        let policy = PrunePolicy {
+            keep: Some(2),
+            dry_run: true,
+            ..Default::default()
+        };
+        assert_eq!(2, prune_cache(options(), policy).unwrap().len());
+        assert_eq!(4, list_cache(&store_dir).unwrap()[0].checkouts.len());
+    }
+}
@@ -1,9 +1,25 @@
-use std::process::ExitCode;
+use std::{
+    process::ExitCode,
+    time::{Duration, SystemTime},
+};
 
 use clap::{Args, Command, Subcommand};
 use color_eyre::eyre::Report;
 
-use super::{run::run_command, secrets::secret_command, state::AddressIn};
+use super::{
+    api::SuperviseArguments,
+    cache::{cache_repos, dir_size, find_repo, prune_cache, CachedCheckout, PrunePolicy},
+    history::{history_command, parse_time, HistoryFilter},
+    lock::LockWait,
+    lockfile::{update_command, LockMode},
+    logs::logs_command,
+    process::EntrypointOut,
+    resolve::ProcessArguments,
+    run::{rollback_command, run_command, RestartPolicy, RunOptions, SuperviseOptions},
+    secrets::secret_command,
+    state::{AddressIn, StateOptions},
+    watch::{watch_command, WatchOptions},
+};
 
 #[derive(Debug, Args)]
 pub struct NextSub {
@@ -14,11 +30,132 @@ pub struct NextSub {
     /// the current directory or Git root dir
     #[arg(long = "resolve-root")]
     resolve_root: Option<String>,
+
+    /// Only use repositories and checkouts already in the store, without contacting any Git remote. Can also
+    /// be enabled by setting TIDPLOY_OFFLINE.
+    #[arg(long = "offline")]
+    offline: bool,
+
+    /// Fail if the lock file next to the root config does not pin exactly the Git addresses in the configs,
+    /// rather than resolving the ones that are not pinned. Use `update` to refresh the pinned commits.
+    #[arg(long = "locked")]
+    locked: bool,
     // /// Location relative to state root to stop reading configs, inclusive.
     // #[arg(long = "state-root")]
     // state_root: Option<String>,
 }
 
+/// Options that control how the entrypoint process is run. Each of these can also be set in the `argument`
+/// section of a config.
+#[derive(Debug, Args)]
+pub struct ProcessArgs {
+    /// Kill the entrypoint and all processes it started after this many seconds.
+    #[arg(long = "timeout")]
+    timeout: Option<u64>,
+
+    /// Number of times to run the entrypoint again if it fails or times out.
+    #[arg(long = "retries")]
+    retries: Option<u32>,
+
+    /// Seconds to wait before running the entrypoint again.
+    #[arg(long = "retry-delay")]
+    retry_delay: Option<u64>,
+
+    /// Only retry when the entrypoint exits with one of these codes. Can be given multiple times. By default
+    /// any failure is retried.
+    #[arg(long = "retry-on")]
+    retry_on: Vec<i32>,
+
+    /// Start the entrypoint from an empty environment, rather than the environment tidploy is run in. Use
+    /// --clean-env=false to turn it off when the config turns it on.
+    #[arg(
+        long = "clean-env",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    clean_env: Option<bool>,
+
+    /// Variable to copy from the current environment when using --clean-env. Can be given multiple times.
+    /// Ignored with --clean-env=false.
+    #[arg(long = "inherit")]
+    inherit: Vec<String>,
+
+    /// Mask the values of loaded secrets in the output of the entrypoint.
+    #[arg(long = "redact")]
+    redact: bool,
+
+    /// Run the entrypoint in a pseudo-terminal, so that it behaves as if started from an interactive shell.
+    #[arg(long = "pty")]
+    pty: bool,
+
+    /// Also write the output of the entrypoint to a log file, which can be read back with `logs`.
+    #[arg(long = "log")]
+    log: bool,
+
+    /// Number of log files to keep for this address [default: 20].
+    #[arg(long = "log-max-files")]
+    log_max_files: Option<usize>,
+
+    /// Size in bytes after which a log file is continued in a new one [default: 10 MiB].
+    #[arg(long = "log-max-size")]
+    log_max_size: Option<u64>,
+}
+
+impl From<ProcessArgs> for ProcessArguments {
+    fn from(value: ProcessArgs) -> Self {
+        Self {
+            timeout: value.timeout,
+            retries: value.retries,
+            retry_delay: value.retry_delay,
+            retry_on: (!value.retry_on.is_empty()).then_some(value.retry_on),
+            clean_env: value.clean_env,
+            inherit: (!value.inherit.is_empty()).then_some(value.inherit),
+            redact: value.redact.then_some(true),
+            pty: value.pty.then_some(true),
+            log: value.log.then_some(true),
+            log_max_files: value.log_max_files,
+            log_max_size: value.log_max_size,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct SuperviseArgs {
+    /// Keep the entrypoint running, restarting it according to this policy.
+    #[arg(long = "supervise", value_enum)]
+    supervise: Option<RestartPolicy>,
+
+    /// Seconds to wait before the first restart, doubled after every restart [default: 1].
+    #[arg(long = "backoff", requires = "supervise")]
+    backoff: Option<u64>,
+
+    /// Maximum number of seconds to wait between restarts [default: 60].
+    #[arg(long = "backoff-max", requires = "supervise")]
+    backoff_max: Option<u64>,
+
+    /// Stop supervising when the entrypoint fails more than this many times within the crash window
+    /// [default: 5].
+    #[arg(long = "crash-limit", requires = "supervise")]
+    crash_limit: Option<u32>,
+
+    /// Length of the crash window in seconds [default: 60].
+    #[arg(long = "crash-window", requires = "supervise")]
+    crash_window: Option<u64>,
+}
+
+impl SuperviseArgs {
+    fn options(self) -> Option<SuperviseOptions> {
+        let mut args = SuperviseArguments::new(self.supervise?);
+        args.backoff = self.backoff;
+        args.backoff_max = self.backoff_max;
+        args.crash_limit = self.crash_limit;
+        args.crash_window = self.crash_window;
+
+        Some(args.into())
+    }
+}
+
 #[derive(Subcommand, Debug)]
 pub enum NextCommands {
     /// Save secret with key until reboot.
@@ -33,6 +170,11 @@ pub enum NextCommands {
 
         #[arg(long = "state-path")]
         state_path: Option<String>,
+
+        /// Do not follow the address in the config, so the secret is scoped to the config itself. Use this to
+        /// set the token or SSH key of a Git address in that config.
+        #[arg(long = "no-follow")]
+        no_follow: bool,
     },
 
     /// Run an entrypoint or archive created by download/deploy and load secrets
@@ -52,6 +194,12 @@ pub enum NextCommands {
 
         #[arg(short = 'G', long = "GR")]
         git_infer: bool,
+
+        #[command(flatten)]
+        process: ProcessArgs,
+
+        #[command(flatten)]
+        supervise: SuperviseArgs,
     },
 
     Deploy {
@@ -79,7 +227,337 @@ pub enum NextCommands {
 
         #[arg(long = "local")]
         local: bool,
+
+        /// Path outside of the state path to also check out, relative to the resolve root. Can be given
+        /// multiple times.
+        #[arg(long = "include")]
+        include: Vec<String>,
+
+        /// Also check out the submodules inside the checked out paths.
+        #[arg(long = "submodules")]
+        submodules: bool,
+
+        /// Also download the Git LFS files that are checked out. Requires git-lfs to be installed.
+        #[arg(long = "lfs")]
+        lfs: bool,
+
+        /// If the entrypoint succeeds, make this checkout the current release, which `rollback` can return to.
+        #[arg(long = "release")]
+        release: bool,
+
+        /// Wait for other deploys of the same target to finish (default).
+        #[arg(long = "wait", overrides_with = "no_wait")]
+        wait: bool,
+
+        /// Fail immediately if the same target is already being deployed.
+        #[arg(long = "no-wait")]
+        no_wait: bool,
+
+        #[command(flatten)]
+        process: ProcessArgs,
     },
+
+    /// Keep checking what the ref of a Git address points to and deploy it every time it moves.
+    Watch {
+        git_ref: Option<String>,
+
+        state_path: Option<String>,
+
+        /// Relative path of the executable relative to the resolution root.
+        #[arg(short = 'x', long = "exe")]
+        executable: Option<String>,
+
+        /// Working directory for execution of the executable relative to the resolution root.
+        #[arg(long = "exn-path")]
+        execution_path: Option<String>,
+
+        /// Variables to load. Supply as many pairs of <key> <env var name> as needed.
+        #[arg(short, num_args = 2)]
+        variables: Vec<String>,
+
+        #[arg(short = 'c', long = "cwd")]
+        cwd_infer: bool,
+
+        #[arg(long = "repo")]
+        repo: Option<String>,
+
+        #[arg(long = "local")]
+        local: bool,
+
+        /// Make every successful deploy the current release, see `deploy --release`.
+        #[arg(long = "release")]
+        release: bool,
+
+        /// How often to check the remote, e.g. "30s" or "5min".
+        #[arg(long = "interval", default_value = "1min", value_parser = parse_duration)]
+        interval: Duration,
+
+        /// Only deploy a new commit once the ref has pointed to it for this long.
+        #[arg(long = "debounce", default_value = "10s", value_parser = parse_duration)]
+        debounce: Duration,
+
+        /// Minimum time between the start of two deploys.
+        #[arg(long = "min-interval", default_value = "0s", value_parser = parse_duration)]
+        min_interval: Duration,
+
+        /// Stop after this many deploys.
+        #[arg(long = "max-deploys")]
+        max_deploys: Option<usize>,
+
+        #[command(flatten)]
+        process: ProcessArgs,
+    },
+
+    /// Run the release before the current one again, with the arguments it was deployed with. If it
+    /// succeeds, it becomes the current release.
+    Rollback {
+        state_path: Option<String>,
+
+        #[arg(short = 'c', long = "cwd")]
+        cwd_infer: bool,
+
+        #[arg(long = "repo")]
+        repo: Option<String>,
+
+        #[arg(long = "local")]
+        local: bool,
+    },
+
+    /// Print the logged output of the latest run (see --log) of an address.
+    Logs {
+        /// Read the logs of a `deploy` rather than a `run` address, using the same address options.
+        #[arg(long = "deploy")]
+        deploy: bool,
+
+        #[arg(long = "state-path")]
+        state_path: Option<String>,
+
+        /// Same as for `run`, only used without --deploy.
+        #[arg(short = 'G', long = "GR")]
+        git_infer: bool,
+
+        /// Same as for `deploy`, only used with --deploy.
+        #[arg(short = 'c', long = "cwd")]
+        cwd_infer: bool,
+
+        #[arg(long = "repo")]
+        repo: Option<String>,
+
+        #[arg(long = "local")]
+        local: bool,
+
+        /// Number of lines to print from the end of the log.
+        #[arg(short = 'n', long = "lines", default_value_t = 50)]
+        lines: usize,
+    },
+
+    /// Show previous runs and deploys, from oldest to newest.
+    History {
+        /// Only show runs of addresses with this name.
+        #[arg(long = "name")]
+        name: Option<String>,
+
+        /// Only show runs with this state path.
+        #[arg(long = "state-path")]
+        state_path: Option<String>,
+
+        /// Only show runs that started at or after this time (YYYY-MM-DD or RFC 3339, in UTC).
+        #[arg(long = "since")]
+        since: Option<String>,
+
+        /// Only show runs that started before this time (YYYY-MM-DD or RFC 3339, in UTC).
+        #[arg(long = "until")]
+        until: Option<String>,
+
+        /// Only show runs that did not succeed.
+        #[arg(long = "failed")]
+        failed: bool,
+
+        /// Number of most recent runs to show.
+        #[arg(short = 'n', long = "limit", default_value_t = 20)]
+        limit: usize,
+
+        /// Print the full entries as JSON lines.
+        #[arg(long = "json")]
+        json: bool,
+    },
+
+    /// Resolve the Git addresses in the configs again and pin them to their current commits in the lock file
+    /// next to the root config. With --deploy, the commit of the root Git address is pinned in the store.
+    Update {
+        /// Pin a `deploy` rather than a `run` address, using the same address options.
+        #[arg(long = "deploy")]
+        deploy: bool,
+
+        #[arg(long = "state-path")]
+        state_path: Option<String>,
+
+        /// Same as for `run`, only used without --deploy.
+        #[arg(short = 'G', long = "GR")]
+        git_infer: bool,
+
+        /// Same as for `deploy`, only used with --deploy.
+        #[arg(short = 'c', long = "cwd")]
+        cwd_infer: bool,
+
+        #[arg(long = "repo")]
+        repo: Option<String>,
+
+        #[arg(long = "local")]
+        local: bool,
+
+        /// Same as for `deploy`, only used with --deploy.
+        #[arg(long = "git-ref")]
+        git_ref: Option<String>,
+    },
+
+    /// Manage the repositories and commit checkouts in the store directory.
+    Cache {
+        #[clap(subcommand)]
+        subcommand: CacheCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommands {
+    /// List all repositories and their checkouts, from newest to oldest.
+    List,
+
+    /// Show the details and disk usage of a repository.
+    Inspect {
+        /// Name or directory name of the repository.
+        repo: String,
+    },
+
+    /// Remove old checkouts. Checkouts of a release are never removed.
+    Prune {
+        /// Keep this many of the newest checkouts of every repository. A commit checked out with different
+        /// paths counts as several checkouts.
+        #[arg(long = "keep")]
+        keep: Option<usize>,
+
+        /// Only remove checkouts created longer ago than this, e.g. "30days" or "2weeks".
+        #[arg(long = "older-than", value_parser = parse_duration)]
+        older_than: Option<Duration>,
+
+        /// Only prune checkouts of this repository (name or directory name).
+        #[arg(long = "repo")]
+        repo: Option<String>,
+
+        /// Only show what would be removed.
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+    },
+}
+
+/// Parses durations like "30s" or "2weeks" for clap, which already mentions the invalid input in its error.
+fn parse_duration(input: &str) -> Result<Duration, humantime::DurationError> {
+    humantime::parse_duration(input)
+}
+
+/// The exit code of the entrypoint, as a shell reports it: 124 if it was killed because it timed out (like
+/// `timeout` does) and 128 plus the signal if it was killed by one.
+fn exit_code(out: &EntrypointOut) -> Result<ExitCode, Report> {
+    if out.attempts.last().is_some_and(|a| a.timed_out) {
+        return Ok(ExitCode::from(124));
+    }
+    // If [process::ExitCode::from_raw] gets stabilized this can be simplified
+    if let Some(code) = out.exit.code() {
+        return Ok(ExitCode::from(u8::try_from(code)?));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = out.exit.signal() {
+            return Ok(ExitCode::from(u8::try_from(128 + signal)?));
+        }
+    }
+
+    Ok(ExitCode::FAILURE)
+}
+
+fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+fn print_checkout(checkout: &CachedCheckout) {
+    let commit = &checkout.meta.commit;
+    let commit_short = &commit[..commit.len().min(10)];
+    let release = if checkout.release { " (release)" } else { "" };
+    println!(
+        "  {} {} {}{}",
+        commit_short,
+        format_time(checkout.created),
+        checkout.meta.paths.join(","),
+        release
+    );
+}
+
+fn cache_command(subcommand: CacheCommands) -> Result<(), Report> {
+    match subcommand {
+        CacheCommands::List => {
+            for repo in cache_repos(None)? {
+                println!(
+                    "{} ({}) {} checkouts, {}",
+                    repo.meta.name,
+                    repo.dir_name,
+                    repo.checkouts.len(),
+                    repo.meta.url
+                );
+                for checkout in &repo.checkouts {
+                    print_checkout(checkout);
+                }
+            }
+        }
+        CacheCommands::Inspect { repo } => {
+            let repo = find_repo(cache_repos(None)?, &repo)?;
+            println!("Name: {}", repo.meta.name);
+            println!("URL: {}", repo.meta.url);
+            println!("Clone: {} ({} bytes)", repo.path, dir_size(&repo.path)?);
+            println!("Checkouts:");
+            for checkout in &repo.checkouts {
+                println!(
+                    "  {}{}",
+                    checkout.meta.commit,
+                    if checkout.release { " (release)" } else { "" }
+                );
+                println!("    Created: {}", format_time(checkout.created));
+                if let Some(git_ref) = &checkout.meta.git_ref {
+                    println!("    Ref: {}", git_ref);
+                }
+                println!("    Paths: {}", checkout.meta.paths.join(","));
+                if let Some(version) = &checkout.meta.tidploy_version {
+                    println!("    Created by: tidploy {}", version);
+                }
+                println!(
+                    "    Location: {} ({} bytes)",
+                    checkout.path,
+                    dir_size(&checkout.path)?
+                );
+            }
+        }
+        CacheCommands::Prune {
+            keep,
+            older_than,
+            repo,
+            dry_run,
+        } => {
+            let policy = PrunePolicy {
+                keep,
+                older_than,
+                repo,
+                dry_run,
+            };
+            let removed = prune_cache(None, policy)?;
+            let verb = if dry_run { "Would remove" } else { "Removed" };
+            println!("{} {} checkouts.", verb, removed.len());
+            for checkout in &removed {
+                print_checkout(checkout);
+            }
+        }
+    }
+
+    Ok(())
 }
 
 #[derive(Subcommand, Debug)]
@@ -101,17 +579,38 @@ pub fn match_command(next_sub: NextSub, _cmd: Command) -> Result<ExitCode, Repor
     let NextSub {
         subcommand,
         resolve_root,
+        offline,
+        locked,
     } = next_sub;
+    let default_options = StateOptions::default();
+    let state_options = StateOptions {
+        offline: offline || default_options.offline,
+        lock_mode: if locked {
+            LockMode::Locked
+        } else {
+            default_options.lock_mode
+        },
+        ..default_options
+    };
 
     match subcommand {
         NextCommands::Secret {
             key,
             cwd_infer,
             state_path,
+            no_follow,
         } => {
             let addr_in = AddressIn::from_secret(resolve_root, state_path);
 
-            secret_command(addr_in, cwd_infer, None, None, key, None)?;
+            secret_command(
+                addr_in,
+                cwd_infer,
+                Some(state_options),
+                None,
+                key,
+                None,
+                !no_follow,
+            )?;
 
             Ok(ExitCode::from(0))
         }
@@ -121,19 +620,25 @@ pub fn match_command(next_sub: NextSub, _cmd: Command) -> Result<ExitCode, Repor
             execution_path,
             git_infer,
             state_path,
+            process,
+            supervise,
         } => {
             let addr_in = AddressIn::from_run(resolve_root, state_path);
+            let run_options = RunOptions {
+                process_args: process.into(),
+                supervise: supervise.options(),
+                ..Default::default()
+            };
             let out = run_command(
                 addr_in,
                 git_infer,
-                None,
+                state_options,
+                run_options,
                 executable,
                 execution_path,
                 variables,
             )?;
-            let code = u8::try_from(out.exit.code().unwrap_or(0))?;
-
-            Ok(ExitCode::from(code))
+            exit_code(&out)
         }
         NextCommands::Deploy {
             executable,
@@ -144,20 +649,201 @@ pub fn match_command(next_sub: NextSub, _cmd: Command) -> Result<ExitCode, Repor
             repo,
             git_ref,
             state_path,
+            include,
+            submodules,
+            lfs,
+            release,
+            wait: _,
+            no_wait,
+            process,
         } => {
-            let addr_in = AddressIn::from_deploy(repo, local, git_ref, resolve_root, state_path);
+            let addr_in = AddressIn::from_deploy(repo, local, git_ref, resolve_root, state_path)
+                .with_checkout(include, submodules, lfs);
+            let lock = if no_wait {
+                LockWait::NoWait
+            } else {
+                LockWait::Wait
+            };
+            let run_options = RunOptions {
+                process_args: process.into(),
+                release,
+                lock: Some(lock),
+                ..Default::default()
+            };
             let out = run_command(
                 addr_in,
                 !cwd_infer,
-                None,
+                state_options,
+                run_options,
                 executable,
                 execution_path,
                 variables,
             )?;
-            // If [process::ExitCode::from_raw] gets stabilized this can be simplified
-            let code = u8::try_from(out.exit.code().unwrap_or(0))?;
+            exit_code(&out)
+        }
+        NextCommands::Watch {
+            git_ref,
+            state_path,
+            executable,
+            execution_path,
+            variables,
+            cwd_infer,
+            repo,
+            local,
+            release,
+            interval,
+            debounce,
+            min_interval,
+            max_deploys,
+            process,
+        } => {
+            let addr_in = AddressIn::from_deploy(repo, local, git_ref, resolve_root, state_path);
+            let watch_options = WatchOptions {
+                interval,
+                debounce,
+                min_interval,
+                max_deploys,
+            };
+            let run_options = RunOptions {
+                process_args: process.into(),
+                release,
+                lock: Some(LockWait::Wait),
+                ..Default::default()
+            };
+            let mut failed = false;
+            watch_command(
+                addr_in,
+                !cwd_infer,
+                Some(state_options),
+                watch_options,
+                |addr_in, state_options| {
+                    run_command(
+                        addr_in,
+                        !cwd_infer,
+                        state_options,
+                        run_options.clone(),
+                        executable.clone(),
+                        execution_path.clone(),
+                        variables.clone(),
+                    )
+                },
+                |deploy| failed = deploy.out.as_ref().is_none_or(|o| !o.exit.success()),
+            )?;
+
+            Ok(ExitCode::from(u8::from(failed)))
+        }
+        NextCommands::Rollback {
+            state_path,
+            cwd_infer,
+            repo,
+            local,
+        } => {
+            let addr_in = AddressIn::from_deploy(repo, local, None, resolve_root, state_path);
+            let out = rollback_command(addr_in, !cwd_infer, Some(state_options))?;
+            exit_code(&out)
+        }
+        NextCommands::Update {
+            deploy,
+            state_path,
+            git_infer,
+            cwd_infer,
+            repo,
+            local,
+            git_ref,
+        } => {
+            let (addr_in, git_infer) = if deploy {
+                (
+                    AddressIn::from_deploy(repo, local, git_ref, resolve_root, state_path),
+                    !cwd_infer,
+                )
+            } else {
+                (AddressIn::from_run(resolve_root, state_path), git_infer)
+            };
+            let pins = update_command(addr_in, git_infer, Some(state_options))?;
+            if pins.is_empty() {
+                println!("No Git addresses to pin.");
+            }
+            for pin in pins {
+                let tag = pin.tag.map(|t| format!(" ({})", t)).unwrap_or_default();
+                println!("{} {}{} {}", pin.url, pin.git_ref, tag, pin.commit);
+            }
+
+            Ok(ExitCode::from(0))
+        }
+        NextCommands::Cache { subcommand } => {
+            cache_command(subcommand)?;
+
+            Ok(ExitCode::from(0))
+        }
+        NextCommands::Logs {
+            deploy,
+            state_path,
+            git_infer,
+            cwd_infer,
+            repo,
+            local,
+            lines,
+        } => {
+            let (addr_in, git_infer) = if deploy {
+                (
+                    AddressIn::from_deploy(repo, local, None, resolve_root, state_path),
+                    !cwd_infer,
+                )
+            } else {
+                (AddressIn::from_run(resolve_root, state_path), git_infer)
+            };
+
+            match logs_command(addr_in, git_infer, None, Some(lines))? {
+                Some(log) => println!("{}", log),
+                None => println!("No logged runs found for this address."),
+            }
 
-            Ok(ExitCode::from(code))
+            Ok(ExitCode::from(0))
+        }
+        NextCommands::History {
+            name,
+            state_path,
+            since,
+            until,
+            failed,
+            limit,
+            json,
+        } => {
+            let filter = HistoryFilter {
+                name,
+                state_path,
+                since: since.as_deref().map(parse_time).transpose()?,
+                until: until.as_deref().map(parse_time).transpose()?,
+                failed,
+                limit: Some(limit),
+            };
+
+            for entry in history_command(None, filter)? {
+                if json {
+                    println!("{}", serde_json::to_string(&entry)?);
+                } else {
+                    let exit = match (&entry.error, entry.exit_code) {
+                        (Some(_), _) => "error".to_owned(),
+                        (None, Some(code)) => code.to_string(),
+                        (None, None) => "killed".to_owned(),
+                    };
+                    let commit = entry
+                        .commit
+                        .as_deref()
+                        .map_or("-", |c| &c[..c.len().min(10)]);
+                    println!(
+                        "{} exit={} {:.1}s {} {} {}",
+                        entry.timestamp,
+                        exit,
+                        entry.duration_ms as f64 / 1000.0,
+                        entry.name,
+                        commit,
+                        entry.executable
+                    );
+                }
+            }
+
+            Ok(ExitCode::from(0))
         }
     }
 }
@@ -2,17 +2,38 @@ use std::{collections::HashMap, fs, ops::ControlFlow};
 
 use camino::{Utf8Path, Utf8PathBuf};
 use relative_path::{RelativePath, RelativePathBuf};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tracing::debug;
 
 use crate::{filesystem::WrapToPath, next::errors::WrapConfigErr};
 
 use super::errors::ConfigError;
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum VarDelivery {
+    /// The secret is set as the value of the environment variable.
+    #[default]
+    Env,
+    /// The secret is written to a private file and `<env_name>_FILE` is set to its path.
+    File,
+}
+
+/// What to check the signature of before a Git address is used.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum VerifyMode {
+    /// The annotated tag the ref resolved to, which must point to the commit that is used.
+    Tag,
+    /// The commit the ref resolved to.
+    Commit,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct ConfigVar {
     pub(crate) key: String,
     pub(crate) env_name: String,
+    pub(crate) delivery: Option<VarDelivery>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -29,6 +50,29 @@ pub(crate) struct ArgumentConfig {
     pub(crate) executable: Option<String>,
     pub(crate) execution_path: Option<String>,
     pub(crate) envs: Option<Vec<ConfigVar>>,
+    /// Seconds after which the entrypoint and all processes it started are killed.
+    pub(crate) timeout: Option<u64>,
+    /// Number of times the entrypoint is run again after failing or timing out.
+    pub(crate) retries: Option<u32>,
+    /// Seconds to wait before running the entrypoint again.
+    pub(crate) retry_delay: Option<u64>,
+    /// Exit codes to retry on. If not set, any failure is retried.
+    pub(crate) retry_on: Option<Vec<i32>>,
+    /// Start the entrypoint from an empty environment instead of the environment of the tidploy process.
+    pub(crate) clean_env: Option<bool>,
+    /// Variables copied over from the tidploy process when `clean_env` is set. They are ignored if it is false,
+    /// and it is an error to give them if it is not set at all.
+    pub(crate) inherit: Option<Vec<String>>,
+    /// Mask the values of loaded secrets in the output of the entrypoint.
+    pub(crate) redact: Option<bool>,
+    /// Run the entrypoint in a pseudo-terminal, for programs that behave differently when not in a terminal.
+    pub(crate) pty: Option<bool>,
+    /// Also write the output of the entrypoint to a log file in the store dir.
+    pub(crate) log: Option<bool>,
+    /// Number of log files kept per address.
+    pub(crate) log_max_files: Option<usize>,
+    /// Size in bytes after which a log file is continued in a new one.
+    pub(crate) log_max_size: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -46,6 +90,23 @@ pub(crate) enum ConfigAddress {
         git_ref: String,
         target_path: Option<String>,
         state_path: Option<String>,
+        /// Fail unless the tag or commit has a valid signature.
+        verify: Option<VerifyMode>,
+        /// Allowed signers file (see `gpg.ssh.allowedSignersFile` in git-config) used to verify SSH
+        /// signatures, relative to the config.
+        allowed_signers: Option<String>,
+        /// Paths outside of the state path to also check out, relative to the target path.
+        include_paths: Option<Vec<String>>,
+        /// Also check out the submodules inside the checked out paths.
+        submodules: Option<bool>,
+        /// Also download the Git LFS files that are checked out.
+        lfs: Option<bool>,
+        /// Secret key of a token (or password) to clone private repositories over HTTPS.
+        token: Option<String>,
+        /// Username given together with the token.
+        token_user: Option<String>,
+        /// Secret key of an SSH private key to clone private repositories over SSH.
+        ssh_key: Option<String>,
         // arg_root: Option<String>,
         // arg_path: Option<String>,
     },
@@ -134,22 +195,14 @@ pub(crate) fn merge_vars(
     root_vars: Vec<ConfigVar>,
     overwrite_vars: Vec<ConfigVar>,
 ) -> Vec<ConfigVar> {
-    let mut vars_map: HashMap<String, String> = root_vars
-        .iter()
-        .map(|v| (v.key.clone(), v.env_name.clone()))
-        .collect();
+    let mut vars_map: HashMap<String, ConfigVar> =
+        root_vars.into_iter().map(|v| (v.key.clone(), v)).collect();
 
     for cfg_var in overwrite_vars {
-        vars_map.insert(cfg_var.key, cfg_var.env_name);
+        vars_map.insert(cfg_var.key.clone(), cfg_var);
     }
 
-    vars_map
-        .into_iter()
-        .map(|(k, v)| ConfigVar {
-            env_name: v,
-            key: k,
-        })
-        .collect()
+    vars_map.into_values().collect()
 }
 
 fn overwrite_arguments(
@@ -162,12 +215,34 @@ fn overwrite_arguments(
         overwrite_option(root_config.execution_path, overwrite_config.execution_path);
     let executable = overwrite_option(root_config.executable, overwrite_config.executable);
     let envs = merge_option(root_config.envs, overwrite_config.envs, &merge_vars);
+    let timeout = overwrite_option(root_config.timeout, overwrite_config.timeout);
+    let retries = overwrite_option(root_config.retries, overwrite_config.retries);
+    let retry_delay = overwrite_option(root_config.retry_delay, overwrite_config.retry_delay);
+    let retry_on = overwrite_option(root_config.retry_on, overwrite_config.retry_on);
+    let clean_env = overwrite_option(root_config.clean_env, overwrite_config.clean_env);
+    let inherit = overwrite_option(root_config.inherit, overwrite_config.inherit);
+    let redact = overwrite_option(root_config.redact, overwrite_config.redact);
+    let pty = overwrite_option(root_config.pty, overwrite_config.pty);
+    let log = overwrite_option(root_config.log, overwrite_config.log);
+    let log_max_files = overwrite_option(root_config.log_max_files, overwrite_config.log_max_files);
+    let log_max_size = overwrite_option(root_config.log_max_size, overwrite_config.log_max_size);
 
     ArgumentConfig {
         scope,
         executable,
         execution_path,
         envs,
+        timeout,
+        retries,
+        retry_delay,
+        retry_on,
+        clean_env,
+        inherit,
+        redact,
+        pty,
+        log,
+        log_max_files,
+        log_max_size,
     }
 }
 
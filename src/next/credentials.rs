@@ -0,0 +1,197 @@
+use std::{collections::HashMap, env, io, path::Path};
+
+use tracing::debug;
+
+use super::{
+    errors::{StateError, StateErrorKind, WrapStateErr},
+    resolve::SecretScope,
+    secrets::{get_secret, SecretFiles},
+};
+
+/// Username given together with a token if none is configured. Most Git hosts accept any username for a
+/// token, but e.g. GitLab deploy tokens need their own.
+const DEFAULT_TOKEN_USER: &str = "git";
+
+/// Keys of the secrets used to authenticate to the remote of a Git address.
+#[derive(Debug, Clone)]
+pub(crate) struct CredentialKeys {
+    /// A token or password, used for HTTPS remotes.
+    pub(crate) token: Option<String>,
+    pub(crate) token_user: Option<String>,
+    /// An SSH private key, used for SSH remotes.
+    pub(crate) ssh_key: Option<String>,
+    /// The secret scope of the config that contains the address.
+    pub(crate) scope: SecretScope,
+}
+
+/// Environment variables that make Git use credentials from the secret store when it contacts a remote. The
+/// credentials are written to private files, which are removed when this is dropped.
+#[derive(Default)]
+pub(crate) struct GitCredentials {
+    pub(crate) envs: HashMap<String, String>,
+    files: Option<SecretFiles>,
+}
+
+impl GitCredentials {
+    /// Whether Git should be run with its default credentials.
+    #[cfg_attr(not(feature = "gix"), allow(dead_code))]
+    pub(crate) fn is_default(&self) -> bool {
+        self.envs.is_empty()
+    }
+
+    pub(crate) fn load(keys: &CredentialKeys) -> Result<Self, StateError> {
+        let mut credentials = Self::default();
+        if let Some(key) = &keys.token {
+            let token = get_secret(&keys.scope, key)
+                .to_state_err("Failed to get token for Git address from secret store.")?;
+            let user = keys.token_user.as_deref().unwrap_or(DEFAULT_TOKEN_USER);
+            credentials
+                .add_token(user, &token)
+                .to_state_err("Failed to write token for Git.")?;
+        }
+        if let Some(key) = &keys.ssh_key {
+            let ssh_key = get_secret(&keys.scope, key)
+                .to_state_err("Failed to get SSH key for Git address from secret store.")?;
+            credentials
+                .add_ssh_key(&ssh_key)
+                .to_state_err("Failed to write SSH key for Git.")?;
+        }
+
+        Ok(credentials)
+    }
+
+    fn files(&mut self) -> io::Result<&SecretFiles> {
+        match self.files {
+            Some(ref files) => Ok(files),
+            None => Ok(self.files.insert(SecretFiles::new()?)),
+        }
+    }
+
+    /// Gives the token to Git through a credential helper, which reads it from a file so that it does not show
+    /// up in the environment or the arguments of any process. The helper is a shell function rather than a
+    /// script, as the directory of the secret files is often mounted `noexec`. It replaces any credential
+    /// helpers from the Git config, which are set through the environment as well.
+    fn add_token(&mut self, user: &str, token: &str) -> io::Result<()> {
+        let token_path = self.files()?.write("git_token", token)?;
+        let token_path = path_str(&token_path)?;
+        debug!("Using token from secret store at {}", token_path);
+
+        let helper = format!(
+            "!f() {{ test \"$1\" = get && printf 'username=%s\\npassword=%s\\n' {} \"$(cat {})\"; }}; f",
+            shell_quote(user),
+            shell_quote(&token_path)
+        );
+        // An empty value clears the list of helpers
+        self.add_config("credential.helper", "");
+        self.add_config("credential.helper", &helper);
+        // Fail instead of waiting for input if the token is rejected
+        self.envs
+            .insert("GIT_TERMINAL_PROMPT".to_owned(), "0".to_owned());
+
+        Ok(())
+    }
+
+    /// Adds a Git config entry using the `GIT_CONFIG_COUNT` environment variables. Git also inherits these
+    /// from the environment tidploy runs in, so the entry is added after any entries set there.
+    fn add_config(&mut self, key: &str, value: &str) {
+        let count: usize = self
+            .envs
+            .get("GIT_CONFIG_COUNT")
+            .cloned()
+            .or_else(|| env::var("GIT_CONFIG_COUNT").ok())
+            .and_then(|c| c.parse().ok())
+            .unwrap_or(0);
+        self.envs
+            .insert(format!("GIT_CONFIG_KEY_{}", count), key.to_owned());
+        self.envs
+            .insert(format!("GIT_CONFIG_VALUE_{}", count), value.to_owned());
+        self.envs
+            .insert("GIT_CONFIG_COUNT".to_owned(), (count + 1).to_string());
+    }
+
+    fn add_ssh_key(&mut self, ssh_key: &str) -> io::Result<()> {
+        // OpenSSH rejects keys without a final newline
+        let ssh_key = if ssh_key.ends_with('\n') {
+            ssh_key.to_owned()
+        } else {
+            format!("{}\n", ssh_key)
+        };
+        let key_path = self.files()?.write("git_ssh_key", &ssh_key)?;
+        let key_path = path_str(&key_path)?;
+        debug!("Using SSH key from secret store at {}", key_path);
+
+        let command = format!(
+            "ssh -i {} -o IdentitiesOnly=yes -o BatchMode=yes",
+            shell_quote(&key_path)
+        );
+        self.envs.insert("GIT_SSH_COMMAND".to_owned(), command);
+
+        Ok(())
+    }
+}
+
+fn path_str(path: &Path) -> io::Result<String> {
+    path.to_str().map(ToOwned::to_owned).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            StateErrorKind::InvalidPath.to_string(),
+        )
+    })
+}
+
+/// Quotes `s` as a single argument for a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{shell_quote, GitCredentials};
+
+    #[test]
+    fn quote() {
+        assert_eq!("'a b'", shell_quote("a b"));
+        assert_eq!("'it'\\''s'", shell_quote("it's"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn credential_helper() {
+        use std::{fs, process::Command};
+
+        let mut credentials = GitCredentials::default();
+        assert!(credentials.is_default());
+        credentials.add_token("deploy user", "s3cr'et").unwrap();
+        credentials.add_ssh_key("KEY").unwrap();
+        // Entries inherited from the environment are kept
+        let inherited: usize = std::env::var("GIT_CONFIG_COUNT")
+            .ok()
+            .and_then(|c| c.parse().ok())
+            .unwrap_or(0);
+        assert_eq!(
+            (inherited + 2).to_string(),
+            credentials.envs["GIT_CONFIG_COUNT"]
+        );
+        let value =
+            |i: usize| credentials.envs[&format!("GIT_CONFIG_VALUE_{}", inherited + i)].clone();
+        assert_eq!("", value(0));
+        let helper = value(1);
+        assert!(!helper.contains("s3cr"));
+
+        // Git runs a helper starting with `!` using the shell, with the action appended
+        let run = |action: &str| {
+            let script = format!("{} {}", helper.strip_prefix('!').unwrap(), action);
+            let out = Command::new("sh").arg("-c").arg(script).output().unwrap();
+            String::from_utf8(out.stdout).unwrap()
+        };
+        assert_eq!("username=deploy user\npassword=s3cr'et\n", run("get"));
+        assert_eq!("", run("store"));
+
+        let ssh_command = &credentials.envs["GIT_SSH_COMMAND"];
+        let key_path = ssh_command.split('\'').nth(1).unwrap().to_owned();
+        assert_eq!("KEY\n", fs::read_to_string(&key_path).unwrap());
+
+        drop(credentials);
+        assert!(!fs::exists(&key_path).unwrap());
+    }
+}
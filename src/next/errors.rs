@@ -24,8 +24,9 @@ pub(crate) struct SecretKeyringError {
 #[error("{msg}\n{source}")]
 pub(crate) struct StateError {
     pub(crate) msg: String,
-    // This traced error means that traces up to the creation of the specific kind will also be tracked
-    pub(crate) source: TracedError<StateErrorKind>,
+    // This traced error means that traces up to the creation of the specific kind will also be tracked. It is
+    // boxed, as it is large and only created on the error path
+    pub(crate) source: Box<TracedError<StateErrorKind>>,
 }
 
 #[derive(ThisError, Debug)]
@@ -44,6 +45,10 @@ pub(crate) enum StateErrorKind {
     Config(#[from] ConfigError),
     #[error("{0}")]
     Address(#[from] AddressError),
+    #[error("Lock file is out of date! {0}")]
+    LockOutdated(String),
+    #[error("Invalid run arguments! {0}")]
+    InvalidRunArgs(String),
 }
 
 pub(crate) trait WrapStateErr<T, E> {
@@ -59,7 +64,7 @@ where
             Ok(t) => Ok(t),
             Err(e) => Err(StateError {
                 msg: msg.into(),
-                source: e.into().into(),
+                source: Box::new(e.into().into()),
             }),
         }
     }
@@ -102,6 +107,26 @@ pub(crate) enum GitError {
     Process(#[from] GitProcessError),
     #[error("Error with the filesystem before running Git: {0}")]
     IO(#[from] ContextIOError),
+    #[error("Not available offline: {0}")]
+    Offline(String),
+    #[error("Invalid Git ref: {0}")]
+    InvalidRef(String),
+    #[error("Signature verification failed: {0}")]
+    Unverified(String),
+}
+
+#[cfg(feature = "gix")]
+#[derive(ThisError, Debug)]
+pub(crate) enum GixError {
+    #[error("Not supported by the native Git backend: {0}")]
+    Unsupported(String),
+    #[error("Native Git operation {op} failed! {source}")]
+    Failed {
+        op: &'static str,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("Filesystem error in native Git operation! {0}")]
+    IO(#[from] IOError),
 }
 
 #[derive(ThisError, Debug)]
@@ -117,8 +142,12 @@ pub(crate) enum ConfigErrorKind {
     IO(#[from] IOError),
     #[error("Failed to parse config TOML! {0}")]
     TOMLDecode(#[from] toml::de::Error),
+    #[error("Failed to serialize TOML! {0}")]
+    TOMLEncode(#[from] toml::ser::Error),
     #[error("Failed to parse config JSON! {0}")]
     JSONDecode(#[from] serde_json::Error),
+    #[error("Invalid value in config! {0}")]
+    InvalidValue(String),
 }
 
 pub(crate) trait WrapConfigErr<T, E> {
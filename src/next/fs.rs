@@ -1,6 +1,6 @@
 use camino::Utf8PathBuf;
 use directories::ProjectDirs;
-use std::{env, sync::OnceLock};
+use std::{env, path::PathBuf, sync::OnceLock};
 
 pub(crate) struct Dirs {
     pub(crate) cache: Utf8PathBuf,
@@ -22,3 +22,17 @@ pub(crate) fn get_dirs() -> &'static Dirs {
         Dirs { cache, tmp }
     })
 }
+
+/// Base directory for short-lived files containing secrets. Where available, this is memory-backed so the
+/// secrets never touch the disk.
+pub(crate) fn secret_files_base() -> PathBuf {
+    let shm = PathBuf::from("/dev/shm");
+    if cfg!(target_os = "linux") && shm.is_dir() {
+        return shm;
+    }
+
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir),
+        None => env::temp_dir(),
+    }
+}
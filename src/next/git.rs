@@ -1,7 +1,9 @@
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use relative_path::RelativePathBuf;
 use spinoff::{spinners, Spinner};
 use tracing::debug;
+#[cfg(feature = "gix")]
+use tracing::warn;
 
 use crate::{
     filesystem::WrapToPath,
@@ -9,16 +11,23 @@ use crate::{
 };
 
 use super::{
+    config::VerifyMode,
+    credentials::GitCredentials,
     errors::{GitError, GitProcessError, StateError},
+    giturl::GitUrl,
+    lock::{lock_file, lock_file_shared, sibling_lock_path},
+    lockfile::{GitPin, Lockfile},
+    meta::{read_meta, write_meta, CheckoutMeta, RepoMeta, StoreMeta},
     process::process_complete_output,
-    state::{parse_url_name, GitAddress, State, StateStep},
+    refs::RefSelector,
+    state::{GitAddress, ResolvedAddress, State, StateStep, Verify},
 };
+#[cfg(feature = "gix")]
+use super::{errors::GixError, gitoxide};
 use core::fmt::Debug;
 use std::{
     ffi::OsStr,
-    fs::{self, create_dir_all, remove_dir_all, File},
-    io::{self, Write},
-    path::Path,
+    fs::{self, create_dir_all, remove_dir_all},
 };
 
 fn run_git<S: AsRef<OsStr> + Debug>(
@@ -26,7 +35,17 @@ fn run_git<S: AsRef<OsStr> + Debug>(
     args: Vec<S>,
     op_name: &'static str,
 ) -> Result<String, GitError> {
-    let git_out = process_complete_output(working_dir, "git", args);
+    run_git_auth(working_dir, args, &GitCredentials::default(), op_name)
+}
+
+/// Runs a Git operation that might contact a remote, authenticating with `credentials`.
+fn run_git_auth<S: AsRef<OsStr> + Debug>(
+    working_dir: &Utf8Path,
+    args: Vec<S>,
+    credentials: &GitCredentials,
+    op_name: &'static str,
+) -> Result<String, GitError> {
+    let git_out = process_complete_output(working_dir, "git", args, &credentials.envs);
 
     match git_out {
         Ok(out) => {
@@ -44,7 +63,67 @@ fn run_git<S: AsRef<OsStr> + Debug>(
     }
 }
 
+/// Which implementation runs the Git operations, set with the `TIDPLOY_GIT_BACKEND` environment variable to
+/// either `gix` or `cli`. When built with the `gix` feature the native backend is the default.
+#[cfg(feature = "gix")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GitBackend {
+    Cli,
+    Gix,
+}
+
+#[cfg(feature = "gix")]
+fn git_backend() -> GitBackend {
+    match std::env::var("TIDPLOY_GIT_BACKEND").as_deref() {
+        Ok("cli") => GitBackend::Cli,
+        Ok("gix") | Err(_) => GitBackend::Gix,
+        Ok(other) => {
+            warn!("Unknown Git backend {}, using gix.", other);
+            GitBackend::Gix
+        }
+    }
+}
+
+/// Runs `native` if the native backend is selected. Returns None if the CLI should be used instead, either
+/// because it was selected or because the native backend could not do it.
+#[cfg(feature = "gix")]
+fn try_native<T>(op_name: &str, native: impl FnOnce() -> Result<T, GixError>) -> Option<T> {
+    if git_backend() != GitBackend::Gix {
+        return None;
+    }
+    match native() {
+        Ok(out) => Some(out),
+        Err(GixError::Unsupported(reason)) => {
+            debug!("Cannot {} natively ({}), using git CLI.", op_name, reason);
+            None
+        }
+        Err(e) => {
+            warn!("Native {} failed, falling back to git CLI. {}", op_name, e);
+            None
+        }
+    }
+}
+
+/// Like [try_native], but for operations that contact the remote. Credentials from the secret store are
+/// only passed to the git CLI.
+#[cfg(feature = "gix")]
+fn try_native_remote<T>(
+    op_name: &str,
+    credentials: &GitCredentials,
+    native: impl FnOnce() -> Result<T, GixError>,
+) -> Option<T> {
+    if !credentials.is_default() {
+        debug!("Using git CLI to {} with credentials.", op_name);
+        return None;
+    }
+    try_native(op_name, native)
+}
+
 pub(crate) fn git_root_dir(path: &Utf8Path) -> Result<String, GitError> {
+    #[cfg(feature = "gix")]
+    if let Some(root) = try_native("get git root dir", || gitoxide::git_root_dir(path)) {
+        return Ok(root);
+    }
     let args = vec!["rev-parse", "--show-toplevel"];
 
     run_git(path, args, "get git root dir")
@@ -54,6 +133,7 @@ pub(crate) fn repo_clone(
     current_dir: &Utf8Path,
     target_name: &str,
     repo_url: &str,
+    credentials: &GitCredentials,
 ) -> Result<(), GitError> {
     debug!(
         "Cloning repository {} directory at target {}",
@@ -70,6 +150,18 @@ pub(crate) fn repo_clone(
     })?;
     let mut sp = Spinner::new(spinners::Line, "Cloning repository...", None);
 
+    #[cfg(feature = "gix")]
+    {
+        let target_dir = current_dir.join(target_name);
+        let native = try_native_remote("clone", credentials, || {
+            gitoxide::repo_clone(&target_dir, repo_url)
+        });
+        if native.is_some() {
+            sp.success("Repository cloned!");
+            return Ok(());
+        }
+    }
+
     let clone_args = vec![
         "clone",
         "--filter=tree:0",
@@ -78,7 +170,7 @@ pub(crate) fn repo_clone(
         repo_url,
         target_name,
     ];
-    run_git(current_dir, clone_args, "partial clone sparse")?;
+    run_git_auth(current_dir, clone_args, credentials, "partial clone sparse")?;
     let target_dir = current_dir.join(target_name);
     let checkout_args = vec!["sparse-checkout", "init", "--cone"];
     run_git(&target_dir, checkout_args, "partial clone sparse")?;
@@ -88,130 +180,555 @@ pub(crate) fn repo_clone(
     Ok(())
 }
 
-pub(crate) fn git_fetch(repo_dir: &Utf8Path) -> Result<(), GitError> {
+pub(crate) fn git_fetch(repo_dir: &Utf8Path, credentials: &GitCredentials) -> Result<(), GitError> {
     let mut sp = Spinner::new(spinners::Line, "Running git fetch...", None);
 
+    #[cfg(feature = "gix")]
+    if try_native_remote("fetch", credentials, || gitoxide::git_fetch(repo_dir)).is_some() {
+        sp.success("Fetched!");
+        return Ok(());
+    }
+
     let clone_args = vec!["fetch"];
-    run_git(repo_dir, clone_args, "fetch")?;
+    run_git_auth(repo_dir, clone_args, credentials, "fetch")?;
 
     sp.success("Fetched!");
 
     Ok(())
 }
 
-pub(crate) fn checkout(repo_dir: &Utf8Path, checkout_sha: &str) -> Result<(), GitError> {
+/// Checks out `checkout_sha`. As the clone is treeless, this fetches the missing trees and blobs from origin,
+/// so it needs the `credentials`.
+pub(crate) fn checkout(
+    repo_dir: &Utf8Path,
+    checkout_sha: &str,
+    credentials: &GitCredentials,
+) -> Result<(), GitError> {
     let mut sp = Spinner::new(spinners::Line, "Checking out...", None);
 
     let clone_args = vec!["checkout", checkout_sha];
-    run_git(repo_dir, clone_args, "checkout")?;
+    run_git_auth(repo_dir, clone_args, credentials, "checkout")?;
 
     sp.success("Checked out!");
 
     Ok(())
 }
 
-pub(crate) fn sparse_checkout(repo_dir: &Utf8Path, mut paths: Vec<&str>) -> Result<(), GitError> {
+pub(crate) fn sparse_checkout(
+    repo_dir: &Utf8Path,
+    mut paths: Vec<&str>,
+    credentials: &GitCredentials,
+) -> Result<(), GitError> {
     let mut sp = Spinner::new(spinners::Line, "Performing sparse checkout...", None);
 
     let mut args = vec!["sparse-checkout", "set"];
     args.append(&mut paths);
-    run_git(repo_dir, args, "checkout")?;
+    run_git_auth(repo_dir, args, credentials, "checkout")?;
 
     sp.success("Sparse checkout done!");
 
     Ok(())
 }
 
+/// Adds a worktree at `worktree_dir` without checking anything out, so that a sparse checkout can be set
+/// first. It shares all objects with the repository, so only the checked out files take up extra space.
+pub(crate) fn worktree_add(
+    repo_dir: &Utf8Path,
+    worktree_dir: &Utf8Path,
+    commit: &str,
+    credentials: &GitCredentials,
+) -> Result<(), GitError> {
+    let args = vec![
+        "worktree",
+        "add",
+        "--no-checkout",
+        "--detach",
+        worktree_dir.as_str(),
+        commit,
+    ];
+    run_git_auth(repo_dir, args, credentials, "worktree add")?;
+
+    Ok(())
+}
+
+/// Removes the administrative files of worktrees whose directory no longer has a `.git` link back to the
+/// repository.
+pub(crate) fn worktree_prune(repo_dir: &Utf8Path) -> Result<(), GitError> {
+    let args = vec!["worktree", "prune"];
+    run_git(repo_dir, args, "worktree prune")?;
+
+    Ok(())
+}
+
+/// Initializes and checks out the submodules inside `paths` (and any submodules they contain) in the
+/// worktree at `worktree_dir`. Returns the paths of all checked out submodules.
+fn submodule_update(
+    worktree_dir: &Utf8Path,
+    paths: &[&str],
+    credentials: &GitCredentials,
+) -> Result<Vec<String>, GitError> {
+    let mut sp = Spinner::new(spinners::Line, "Updating submodules...", None);
+
+    // Submodules are not limited by the sparse checkout, so they are selected by the paths. If the root (an
+    // empty path) is included, that is all of them.
+    let mut args = vec!["submodule", "update", "--init", "--recursive"];
+    if !paths.contains(&"") {
+        args.push("--");
+        args.extend(paths);
+    }
+    run_git_auth(worktree_dir, args, credentials, "submodule update")?;
+    let args = vec![
+        "submodule",
+        "foreach",
+        "--quiet",
+        "--recursive",
+        "echo $displaypath",
+    ];
+    let submodules = run_git(worktree_dir, args, "list submodules")?;
+
+    sp.success("Submodules updated!");
+
+    Ok(submodules.lines().map(ToOwned::to_owned).collect())
+}
+
+/// Downloads the Git LFS files of the checked out commit in the worktree at `worktree_dir`, replacing their
+/// pointer files. Requires git-lfs to be installed.
+fn lfs_pull(worktree_dir: &Utf8Path, credentials: &GitCredentials) -> Result<(), GitError> {
+    let mut sp = Spinner::new(spinners::Line, "Downloading LFS files...", None);
+
+    let args = vec!["lfs", "pull"];
+    run_git_auth(worktree_dir, args, credentials, "lfs pull")?;
+
+    sp.success("LFS files downloaded!");
+
+    Ok(())
+}
+
+/// Writes the files of `commit` in the sparse cone of `paths` to `target_dir`, which will not be a Git
+/// repository. If `submodules` or `lfs` is set, also checks out the submodules inside `paths` or downloads
+/// the LFS files. Should only be called while holding the lock for `repo_dir`.
+fn checkout_paths(
+    repo_dir: &Utf8Path,
+    target_dir: &Utf8Path,
+    commit: &str,
+    paths: Vec<&str>,
+    submodules: bool,
+    lfs: bool,
+    credentials: &GitCredentials,
+) -> Result<(), GitError> {
+    // The native backend supports neither submodules nor LFS. A clone made with credentials is treeless, so
+    // checking it out fetches from origin.
+    #[cfg(feature = "gix")]
+    if !submodules && !lfs {
+        let mut sp = Spinner::new(spinners::Line, "Checking out...", None);
+        let native = try_native_remote("checkout", credentials, || {
+            gitoxide::checkout_paths(repo_dir, target_dir, commit, &paths)
+        });
+        if native.is_some() {
+            sp.success("Checked out!");
+            return Ok(());
+        }
+        sp.clear();
+        // Remove anything written before the native checkout failed
+        if target_dir.exists() {
+            remove_dir_all(target_dir).map_err(|e| {
+                GitError::IO(ContextIOError {
+                    msg: format!("Failed to remove failed checkout {}!", target_dir),
+                    source: e,
+                })
+            })?;
+        }
+    }
+
+    // Clean up after any earlier interrupted checkouts
+    worktree_prune(repo_dir)?;
+    worktree_add(repo_dir, target_dir, commit, credentials)?;
+    let submodule_paths = paths.clone();
+    sparse_checkout(target_dir, paths, credentials)?;
+    checkout(target_dir, commit, credentials)?;
+    let mut git_dirs = vec![String::new()];
+    if submodules {
+        git_dirs.extend(submodule_update(target_dir, &submodule_paths, credentials)?);
+    }
+    if lfs {
+        lfs_pull(target_dir, credentials)?;
+    }
+    // The worktree is detached from the repository, leaving only the checked out files. Pruning also removes
+    // the repositories of the submodules, which are stored with the worktree.
+    for git_dir in git_dirs {
+        let git_file = target_dir.join(git_dir).join(".git");
+        fs::remove_file(&git_file).map_err(|e| {
+            GitError::IO(ContextIOError {
+                msg: format!("Failed to remove {}!", git_file),
+                source: e,
+            })
+        })?;
+    }
+    worktree_prune(repo_dir)
+}
+
+/// A ref as listed by `git ls-remote`, i.e. the object it points to and its full name.
 #[derive(Debug)]
-struct ShaRef {
-    sha: String,
-    tag: String,
+pub(crate) struct ShaRef {
+    pub(crate) sha: String,
+    pub(crate) tag: String,
 }
 
-pub(crate) fn ls_remote(repo_dir: &Utf8Path, pattern: &str) -> Result<String, GitError> {
-    let mut sp = Spinner::new(spinners::Line, "Getting commit hash from remote...", None);
+/// Lists the refs of origin for which `filter` returns true, given the name without a `^{}` suffix. The CLI
+/// runs `git ls-remote` with `args`, which should limit what the remote sends in the same way.
+fn remote_refs(
+    repo_dir: &Utf8Path,
+    args: &[&str],
+    filter: impl Fn(&str) -> bool,
+    credentials: &GitCredentials,
+) -> Result<Vec<ShaRef>, GitError> {
+    #[cfg(feature = "gix")]
+    let native = try_native_remote("ls-remote", credentials, || gitoxide::remote_refs(repo_dir));
+    #[cfg(not(feature = "gix"))]
+    let native = None;
+
+    let sha_refs = match native {
+        Some(sha_refs) => sha_refs,
+        None => {
+            let mut ls_args = vec!["ls-remote"];
+            ls_args.extend_from_slice(args);
+            let out = run_git_auth(repo_dir, ls_args, credentials, "ls-remote origin")?;
 
-    let args = vec!["ls-remote", "origin", pattern];
-    let out = run_git(repo_dir, args, "ls-remote origin")?;
+            out.lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(|s| {
+                    let spl: Vec<&str> = s.split_whitespace().collect();
+                    if spl.len() != 2 {
+                        return Err(GitError::Failed(format!(
+                            "ls-remote returned invalid result: {}",
+                            &out
+                        )));
+                    }
 
-    let split = out.trim().split('\n');
-    let lines: Vec<&str> = split.collect();
-    let mut sha_refs = lines
+                    let sha = spl[0].to_owned();
+                    let tag = spl[1].to_owned();
+
+                    Ok(ShaRef { sha, tag })
+                })
+                .collect::<Result<Vec<ShaRef>, GitError>>()?
+        }
+    };
+
+    Ok(sha_refs
         .into_iter()
-        .map(|s| {
-            let spl: Vec<&str> = s.split_whitespace().collect();
-            if spl.len() != 2 {
-                return Err(GitError::Failed(format!(
-                    "ls-remote returned invalid result: {}",
-                    &out
-                )));
-            }
+        .filter(|r| filter(r.tag.trim_end_matches("^{}")))
+        .collect())
+}
+
+pub(crate) fn ls_remote(
+    repo_dir: &Utf8Path,
+    pattern: &str,
+    credentials: &GitCredentials,
+) -> Result<String, GitError> {
+    let mut sp = Spinner::new(spinners::Line, "Getting commit hash from remote...", None);
+
+    // Peeled tags are only listed when asked for explicitly if there is a pattern
+    let peeled = format!("{}^{{}}", pattern);
+    let sha_refs = remote_refs(
+        repo_dir,
+        &["origin", pattern, &peeled],
+        |name| matches_pattern(name, pattern),
+        credentials,
+    )?;
+    let commit = select_commit(pattern, sha_refs)?;
+
+    sp.success("Got commit hash from remote!");
 
-            let sha = spl[0].to_owned();
-            let tag = spl[1].to_owned();
+    match commit {
+        Some(commit) => Ok(commit),
+        None => resolve_commit(repo_dir, pattern, false, credentials),
+    }
+}
+
+/// Whether `name` is matched by an ls-remote `pattern`, i.e. equals it or ends with it as whole path
+/// components. A full ref (starting with `refs/`) only matches itself.
+pub(crate) fn matches_pattern(name: &str, pattern: &str) -> bool {
+    name == pattern
+        || (!pattern.starts_with("refs/")
+            && name
+                .strip_suffix(pattern)
+                .is_some_and(|rest| rest.ends_with('/')))
+}
+
+/// Refs of the clone as of its last fetch, renamed to how the remote names them, so that they can be used
+/// in place of the result of [ls_remote].
+fn local_refs(repo_dir: &Utf8Path) -> Result<Vec<ShaRef>, GitError> {
+    #[cfg(feature = "gix")]
+    let native = try_native("list local refs", || gitoxide::local_refs(repo_dir));
+    #[cfg(not(feature = "gix"))]
+    let native = None;
+
+    let sha_refs: Vec<ShaRef> = match native {
+        Some(sha_refs) => sha_refs,
+        None => {
+            let args = vec!["show-ref", "--dereference"];
+            let out = run_git(repo_dir, args, "show-ref")?;
+            out.lines()
+                .filter_map(|l| l.split_once(' '))
+                .map(|(sha, tag)| ShaRef {
+                    sha: sha.to_owned(),
+                    tag: tag.to_owned(),
+                })
+                .collect()
+        }
+    };
 
-            Ok(ShaRef { sha, tag })
+    // Clones made by the CLI keep the remote's branches as remote-tracking refs, while their own branches
+    // are never updated
+    let tracking = "refs/remotes/origin/";
+    let has_tracking = sha_refs.iter().any(|r| r.tag.starts_with(tracking));
+    Ok(sha_refs
+        .into_iter()
+        .filter_map(|ShaRef { sha, tag }| {
+            let tag = match tag.strip_prefix(tracking) {
+                Some("HEAD") => "HEAD".to_owned(),
+                Some(branch) => format!("refs/heads/{}", branch),
+                None if has_tracking && tag.starts_with("refs/heads/") => return None,
+                None => tag,
+            };
+            Some(ShaRef { sha, tag })
         })
-        .collect::<Result<Vec<ShaRef>, GitError>>()?;
-    sha_refs.retain(|sr| {
-        // We don't care about the remotes of our remote
-        !(*sr.tag).contains("refs/remotes")
-    });
-
-    let commit = if sha_refs.is_empty() {
-        pattern
-    } else if sha_refs.len() >= 2 && sha_refs.iter().all(|s| s.sha == sha_refs[0].sha) {
-        // All the same, so no ambiguity
-        &sha_refs[0].sha
-    } else if sha_refs.len() == 2 {
-        // We want the one with ^{}
-        if sha_refs[0].tag.ends_with("^{}") {
-            &sha_refs[0].sha
-        } else if sha_refs[1].tag.ends_with("^{}") {
-            &sha_refs[1].sha
-        } else {
-            return Err(GitError::Failed(format!(
-                "Could not choose tag from two options for ls-remote: {:?}",
-                &sha_refs
-            )));
+        .collect())
+}
+
+/// Returns the full hash of `commit` if the clone has it.
+fn local_commit(repo_dir: &Utf8Path, commit: &str) -> Option<String> {
+    #[cfg(feature = "gix")]
+    if let Some(found) = try_native("verify commit", || gitoxide::local_commit(repo_dir, commit)) {
+        return found;
+    }
+    let rev = format!("{}^{{commit}}", commit);
+    let args = vec!["rev-parse", "--verify", "--quiet", &rev];
+    run_git(repo_dir, args, "rev-parse commit")
+        .ok()
+        .map(|c| c.trim().to_owned())
+}
+
+/// Like [ls_remote], but resolves the pattern using only what is already in the clone.
+pub(crate) fn ls_local(repo_dir: &Utf8Path, pattern: &str) -> Result<String, GitError> {
+    let sha_refs = local_refs(repo_dir)?
+        .into_iter()
+        .filter(|r| matches_pattern(r.tag.trim_end_matches("^{}"), pattern))
+        .collect();
+    match select_commit(pattern, sha_refs)? {
+        Some(commit) => local_commit(repo_dir, &commit).ok_or_else(|| {
+            GitError::Offline(format!(
+                "{} points to commit {}, which is not in the cached clone of the repository.",
+                pattern, commit
+            ))
+        }),
+        None => resolve_commit(repo_dir, pattern, true, &GitCredentials::default()),
+    }
+}
+
+/// Whether `name` could be an (abbreviated) commit hash.
+pub(crate) fn is_commit_hash(name: &str) -> bool {
+    (4..=64).contains(&name.len()) && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Expands `commit` to its full hash, fetching first if the clone does not have it and not `offline`. It is
+/// an error if the commit does not exist in the repository.
+fn resolve_commit(
+    repo_dir: &Utf8Path,
+    commit: &str,
+    offline: bool,
+    credentials: &GitCredentials,
+) -> Result<String, GitError> {
+    if !is_commit_hash(commit) {
+        return Err(GitError::InvalidRef(format!(
+            "{} does not match any ref and is not a commit hash.",
+            commit
+        )));
+    }
+    if let Some(full) = local_commit(repo_dir, commit) {
+        return Ok(full);
+    }
+    if offline {
+        return Err(GitError::Offline(format!(
+            "Commit {} is not in the cached clone of the repository.",
+            commit
+        )));
+    }
+
+    git_fetch(repo_dir, credentials)?;
+    local_commit(repo_dir, commit).ok_or_else(|| {
+        GitError::InvalidRef(format!(
+            "Commit {} does not exist in the repository, or the hash is ambiguous.",
+            commit
+        ))
+    })
+}
+
+/// Resolves `git_ref` to a commit, either from the remote or, if `offline`, from the clone. For a `semver:`
+/// or `latest-tag:` selector the tag it chose is also returned.
+pub(crate) fn resolve_ref(
+    repo_dir: &Utf8Path,
+    git_ref: &str,
+    offline: bool,
+    credentials: &GitCredentials,
+) -> Result<(String, Option<String>), GitError> {
+    let selector = RefSelector::parse(git_ref)?;
+    match &selector {
+        RefSelector::Exact(pattern) => {
+            let commit = if offline {
+                ls_local(repo_dir, pattern)?
+            } else {
+                ls_remote(repo_dir, pattern, credentials)?
+            };
+            return Ok((commit, None));
         }
-    } else if sha_refs.len() == 1 {
-        &sha_refs[0].sha
-    // Assume that the pattern given is a commit itself
+        RefSelector::Commit(commit) => {
+            return Ok((
+                resolve_commit(repo_dir, commit, offline, credentials)?,
+                None,
+            ))
+        }
+        RefSelector::Semver(_) | RefSelector::LatestTag(_) => {}
+    }
+
+    let tag_refs = |r: &ShaRef| r.tag.starts_with("refs/tags/");
+    let sha_refs: Vec<ShaRef> = if offline {
+        local_refs(repo_dir)?.into_iter().filter(tag_refs).collect()
     } else {
-        return Err(GitError::Failed(format!(
-            "Pattern is not specific enough, cannot determine commit for {}",
-            pattern
-        )));
+        let mut sp = Spinner::new(spinners::Line, "Getting tags from remote...", None);
+        let sha_refs = remote_refs(
+            repo_dir,
+            &["--tags", "origin"],
+            |name| name.starts_with("refs/tags/"),
+            credentials,
+        )?;
+        sp.success("Got tags from remote!");
+        sha_refs
     };
-    //let rev_parse_arg = format!("{}^{{}}", rev_parse_arg);
-    //println!("rev_parse_arg {}", &rev_parse_arg);
-    //let args = vec!["rev-parse", &rev_parse_arg];
-    //let commit = run_git(repo_dir, args, "rev-parse commit/tag")?;
 
-    sp.success("Got commit hash from remote!");
+    let tags = sha_refs
+        .iter()
+        .filter_map(|r| r.tag.strip_prefix("refs/tags/"))
+        .filter(|t| !t.ends_with("^{}"));
+    let tag = selector
+        .select_tag(tags)
+        .ok_or_else(|| GitError::InvalidRef(format!("No tag matches {}.", git_ref)))?
+        .to_owned();
+
+    let full_tag = format!("refs/tags/{}", tag);
+    let tag_refs = sha_refs
+        .into_iter()
+        .filter(|r| r.tag.trim_end_matches("^{}") == full_tag)
+        .collect();
+    let commit = select_commit(&full_tag, tag_refs)?
+        .ok_or_else(|| GitError::InvalidRef(format!("{} was not listed.", full_tag)))?;
 
-    Ok(commit.to_owned())
+    Ok((commit, Some(tag)))
 }
 
-/// https://stackoverflow.com/a/65192210
-fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<()> {
-    fs::create_dir_all(&dst)?;
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let ty = entry.file_type()?;
-        if ty.is_dir() {
-            copy_dir_all(entry.path(), dst.as_ref().join(entry.file_name()))?;
-        } else {
-            fs::copy(entry.path(), dst.as_ref().join(entry.file_name()))?;
-        }
+/// Checks the signature of `commit`, or of the tag `tag_name` which must point to it, using `git verify-commit`
+/// or `git verify-tag`. SSH signatures are checked against the allowed signers file, GPG signatures against
+/// the keyring of the user. Anything that cannot be verified is an error.
+fn verify_signature(
+    repo_dir: &Utf8Path,
+    verify: &Verify,
+    tag_name: &str,
+    commit: &str,
+    offline: bool,
+    credentials: &GitCredentials,
+) -> Result<(), GitError> {
+    let tag_ref = format!("refs/tags/{}", tag_name);
+    let (target, op) = match verify.mode {
+        VerifyMode::Commit => (commit, "verify-commit"),
+        VerifyMode::Tag => (tag_ref.as_str(), "verify-tag"),
+    };
+    // A tag might have been created after the commit was last fetched
+    if !offline && local_commit(repo_dir, target).as_deref() != Some(commit) {
+        git_fetch(repo_dir, credentials)?;
     }
+    if local_commit(repo_dir, target).as_deref() != Some(commit) {
+        return Err(GitError::Unverified(format!(
+            "{} does not point to commit {} in the clone of the repository.",
+            target, commit
+        )));
+    }
+
+    let signers_config = verify
+        .allowed_signers
+        .as_ref()
+        .map(|p| format!("gpg.ssh.allowedSignersFile={}", p));
+    let mut args = Vec::new();
+    if let Some(signers_config) = &signers_config {
+        args.extend(["-c", signers_config.as_str()]);
+    }
+    args.extend([op, target]);
+
+    let mut sp = Spinner::new(spinners::Line, "Verifying signature...", None);
+    run_git(repo_dir, args, "verify signature").map_err(|e| match e {
+        GitError::Failed(out) => GitError::Unverified(format!("{} {}: {}", op, target, out.trim())),
+        e => e,
+    })?;
+    sp.success("Signature verified!");
+
     Ok(())
 }
 
+/// Finds the newest checkout of `paths_hash` in `checkouts_dir` (the `c/{repo dir name}` directory) that
+/// was created for `git_ref`, or for a commit starting with it. Used when the clone itself is not available.
+fn cached_commit(checkouts_dir: &Utf8Path, paths_hash: &str, git_ref: &str) -> Option<String> {
+    let mut found: Option<CheckoutMeta> = None;
+    for commit_dir in checkouts_dir.read_dir_utf8().ok()?.flatten() {
+        let Ok(Some(StoreMeta::Checkout(meta))) = read_meta(&commit_dir.path().join(paths_hash))
+        else {
+            continue;
+        };
+        let commit = git_ref.strip_prefix("commit:").unwrap_or(git_ref);
+        let matches = meta.git_ref.as_deref() == Some(git_ref) || meta.commit.starts_with(commit);
+        if matches && found.as_ref().is_none_or(|f| meta.created > f.created) {
+            found = Some(meta);
+        }
+    }
+
+    found.map(|meta| meta.commit)
+}
+
+/// Chooses the commit the pattern refers to from the refs that matched it, using the peeled commit of
+/// annotated tags. None if no ref matched, in which case the pattern might be a commit itself. Matching
+/// several refs is only allowed if they all point to the same commit.
+fn select_commit(pattern: &str, sha_refs: Vec<ShaRef>) -> Result<Option<String>, GitError> {
+    let mut commits: Vec<(String, String)> = Vec::new();
+    for ShaRef { sha, tag } in sha_refs {
+        // We don't care about the remotes of our remote
+        if tag.contains("refs/remotes") {
+            continue;
+        }
+        match tag.strip_suffix("^{}") {
+            Some(name) => match commits.iter_mut().find(|(n, _)| n == name) {
+                Some(entry) => entry.1 = sha,
+                None => commits.push((name.to_owned(), sha)),
+            },
+            None if commits.iter().any(|(n, _)| *n == tag) => {}
+            None => commits.push((tag, sha)),
+        }
+    }
+
+    match commits.as_slice() {
+        [] => Ok(None),
+        [(_, sha), rest @ ..] if rest.iter().all(|(_, s)| s == sha) => Ok(Some(sha.clone())),
+        _ => {
+            let names: Vec<&str> = commits.iter().map(|(n, _)| n.as_str()).collect();
+            Err(GitError::InvalidRef(format!(
+                "{} is ambiguous, as it matches {}. Use the full ref or prefix it with branch: or tag:.",
+                pattern,
+                names.join(", ")
+            )))
+        }
+    }
+}
+
 use sha2::{Digest, Sha256};
 
-fn hash_last_n(input: &str, n: usize) -> String {
+pub(crate) fn hash_last_n(input: &str, n: usize) -> String {
     let mut hasher = Sha256::new();
     hasher.update(input);
     let mut result = hasher.finalize();
@@ -222,80 +739,296 @@ fn hash_last_n(input: &str, n: usize) -> String {
     hex
 }
 
+/// The last `n` characters of `input`, or all of it if it is shorter.
 fn str_last_n(input: &str, n: usize) -> &str {
-    let split_pos = input.char_indices().nth_back(n - 1).unwrap().0;
+    let split_pos = match n.checked_sub(1) {
+        Some(back) => input.char_indices().nth_back(back).map_or(0, |(i, _)| i),
+        None => input.len(),
+    };
     &input[split_pos..]
 }
 
-pub(crate) fn get_dir_from_git(
-    address: GitAddress,
-    state_path: RelativePathBuf,
-    // state_root: &RelativePath,
-    store_dir: &Utf8Path,
-) -> Result<State, StateError> {
+/// A directory in the store is only complete once its metadata is written. Anything else is left over from an
+/// interrupted clone or checkout, so it is removed to be created again.
+fn is_complete(dir: &Utf8Path, is_kind: fn(&StoreMeta) -> bool) -> Result<bool, StateError> {
+    match read_meta(dir) {
+        Ok(Some(meta)) if is_kind(&meta) => return Ok(true),
+        Ok(_) => {}
+        Err(e) => debug!("Unreadable metadata in {:?}: {}", dir, e),
+    }
+    if dir.exists() {
+        println!("Removing incomplete directory {:?}...", dir);
+        remove_dir_all(dir).to_state_err("Failed to remove incomplete directory.")?;
+    }
+
+    Ok(false)
+}
+
+/// Lets `build` create the directory at a temporary path next to `dir`, which is renamed to `dir` only if it
+/// succeeds. This way `dir` either does not exist or is complete. Should only be called while holding the
+/// lock for `dir`, as the temporary path is the same for every process.
+fn materialize(
+    dir: &Utf8Path,
+    build: impl FnOnce(&Utf8Path) -> Result<(), StateError>,
+) -> Result<(), StateError> {
+    let mut tmp_path = dir.as_str().to_owned();
+    tmp_path.push_str(".tmp");
+    let tmp_path = Utf8PathBuf::from(tmp_path);
+    if tmp_path.exists() {
+        debug!("Removing leftover temporary directory {:?}", tmp_path);
+        remove_dir_all(&tmp_path).to_state_err("Failed to remove leftover temporary directory.")?;
+    }
+    if let Some(parent) = tmp_path.parent() {
+        create_dir_all(parent).to_state_err("Failed to create parent directory.")?;
+    }
+
+    build(&tmp_path)?;
+
+    fs::rename(&tmp_path, dir).to_state_err("Failed to move directory into place.")
+}
+
+/// Where the repository of a Git address is cloned in the store.
+struct StoreRepo {
+    url: String,
+    name: String,
+    dir_name: String,
+}
+
+fn store_repo(address: &GitAddress) -> Result<StoreRepo, StateError> {
     let url = if address.local {
         git_root_dir(Utf8Path::new(&address.url))
             .to_state_err("Failed to get Git directory from local URL.")?
     } else {
-        address.url
+        address.url.clone()
     };
 
-    let encoded_url = hash_last_n(&url, 8);
-    let name = parse_url_name(&url)
-        .to_state_err("Error passing Git url for determining name.".to_owned())?;
+    // The same repository reached through e.g. HTTPS and SSH shares a store directory
+    let git_url = GitUrl::parse(&url)
+        .to_state_err("Error parsing Git url for determining name.".to_owned())?;
+    let name = git_url.name().to_owned();
+    let encoded_url = hash_last_n(&git_url.identity(), 8);
     let dir_name = format!("{}_{}", name, encoded_url);
 
-    let target_dir = store_dir.join(&dir_name);
-    if !target_dir.exists() {
-        repo_clone(store_dir, &dir_name, &url)
+    Ok(StoreRepo {
+        url,
+        name,
+        dir_name,
+    })
+}
+
+/// Clones the repository to `target_dir` in the store. Should only be called while holding its lock.
+fn clone_into_store(
+    target_dir: &Utf8Path,
+    url: &str,
+    name: &str,
+    credentials: &GitCredentials,
+) -> Result<(), StateError> {
+    let store_dir = target_dir.parent().unwrap_or(target_dir);
+    materialize(target_dir, |tmp_dir| {
+        let tmp_name = tmp_dir.file_name().unwrap_or_default();
+        repo_clone(store_dir, tmp_name, url, credentials)
             .to_state_err("Error cloning repository in address.".to_owned())?;
-        let meta_filename = format!("tidploy_repo_meta_{}", &dir_name);
-        let mut file = File::create(target_dir.join(meta_filename))
-            .to_state_err("Failed to create metadata file!")?;
-        let metadata = format!("url:{}\nname:{}", &url, &name);
-        file.write_all(metadata.as_bytes())
-            .to_state_err("Failed to write to metadatafile!")?;
+        let meta = RepoMeta::new(url.to_owned(), name.to_owned());
+        write_meta(tmp_dir, &StoreMeta::Repo(meta)).to_state_err("Failed to write metadata file!")
+    })
+}
+
+/// Resolves the ref of `address` on its remote, without fetching or checking anything out. The repository is
+/// cloned into the store first if it is not there yet. The URL of the pin is the one recorded when the
+/// address is resolved.
+pub(crate) fn remote_commit(
+    address: &GitAddress,
+    store_dir: &Utf8Path,
+) -> Result<GitPin, StateError> {
+    let StoreRepo {
+        url,
+        name,
+        dir_name,
+    } = store_repo(address)?;
+    let credentials = match &address.credentials {
+        Some(keys) => GitCredentials::load(keys)?,
+        None => GitCredentials::default(),
+    };
+
+    let target_dir = store_dir.join(dir_name);
+    let _repo_lock = lock_file(&sibling_lock_path(&target_dir))
+        .to_state_err("Failed to lock repository directory.")?;
+    if !is_complete(&target_dir, |m| matches!(m, StoreMeta::Repo(_)))? {
+        clone_into_store(&target_dir, &url, &name, &credentials)?;
     }
+    let (commit, tag) = resolve_ref(&target_dir, &address.git_ref, false, &credentials)
+        .to_state_err("Error resolving Git ref.".to_owned())?;
 
-    let commit = ls_remote(&target_dir, &address.git_ref)
-        .to_state_err("Error getting provided tag.".to_owned())?;
-    let commit_short = str_last_n(&commit, 10);
-    let commit_dir = store_dir.join("c");
+    Ok(GitPin {
+        url,
+        git_ref: address.git_ref.clone(),
+        commit,
+        tag,
+    })
+}
+
+pub(crate) fn get_dir_from_git(
+    address: GitAddress,
+    state_path: RelativePathBuf,
+    // state_root: &RelativePath,
+    store_dir: &Utf8Path,
+    offline: bool,
+    lock: &Lockfile,
+) -> Result<State, StateError> {
+    let StoreRepo {
+        url,
+        name,
+        dir_name,
+    } = store_repo(&address)?;
 
     //let state_root_git = address.path.join(state_root);
     let state_path_git = address.path.join(&state_path);
 
+    let include_paths: Vec<RelativePathBuf> = address
+        .include_paths
+        .iter()
+        .map(|p| address.path.join_normalized(p))
+        .collect();
+
     // Paths might not exist, so always do this
     let mut paths = vec![state_path_git.as_str()];
+    paths.extend(include_paths.iter().map(|p| p.as_str()));
     paths.sort();
-    let paths_name = paths.join("_");
+    paths.dedup();
+    let mut paths_name = paths.join("_");
+    // Checkouts with submodules or LFS files have different contents
+    if address.submodules {
+        paths_name.push_str("+submodules");
+    }
+    if address.lfs {
+        paths_name.push_str("+lfs");
+    }
+    let meta_paths: Vec<String> = paths.iter().map(|p| p.to_string()).collect();
     let encoded_paths = hash_last_n(&paths_name, 8);
-    let commit_path = commit_dir
-        .join(&dir_name)
-        .join(commit_short)
-        .join(&encoded_paths);
-
-    if !commit_path.exists() {
-        git_fetch(&target_dir)
-            .to_state_err("Error updating repository to ensure commit exists.".to_owned())?;
-        copy_dir_all(&target_dir, &commit_path)
-            .to_state_err("Error copying main repository before checkout.".to_owned())?;
-        checkout(&commit_path, &commit)
-            .to_state_err("Error checking out new commit.".to_owned())?;
-        sparse_checkout(&commit_path, paths)
-            .to_state_err("Error setting new paths for sparse checkout.".to_owned())?;
-        remove_dir_all(commit_path.join(".git"))
-            .to_state_err("Error removing .git directory.".to_owned())?;
-        let meta_filename = format!("tidploy_deploy_meta_{}_{}", &commit_short, &encoded_paths);
-        let mut file = File::create(commit_path.join(meta_filename))
-            .to_state_err("Failed to create metadata file!")?;
-        let metadata = format!("commit:{}\npaths:{}", &commit, &paths_name);
-        file.write_all(metadata.as_bytes())
-            .to_state_err("Failed to write to metadatafile!")?;
+    let checkouts_dir = store_dir.join("c").join(&dir_name);
+
+    // Credentials are only needed to contact the remote
+    let credentials = match &address.credentials {
+        Some(keys) if !offline => GitCredentials::load(keys)?,
+        _ => GitCredentials::default(),
+    };
+
+    let target_dir = store_dir.join(&dir_name);
+    // Other tidploy processes might be cloning, fetching or adding worktrees to this same repository
+    let _repo_lock = lock_file(&sibling_lock_path(&target_dir))
+        .to_state_err("Failed to lock repository directory.")?;
+    let cloned = is_complete(&target_dir, |m| matches!(m, StoreMeta::Repo(_)))?;
+    if !cloned && !offline {
+        clone_into_store(&target_dir, &url, &name, &credentials)?;
     }
 
+    let pin = lock.pin(&url, &address.git_ref);
+    let (commit, tag) = if let Some(pin) = pin {
+        debug!("Using commit {} pinned by the lock file", pin.commit);
+        (pin.commit.clone(), pin.tag.clone())
+    } else if !offline || cloned {
+        resolve_ref(&target_dir, &address.git_ref, offline, &credentials)
+            .to_state_err("Error resolving Git ref.".to_owned())?
+    } else {
+        let commit = cached_commit(&checkouts_dir, &encoded_paths, &address.git_ref)
+            .ok_or_else(|| {
+                GitError::Offline(format!(
+                    "{} has not been cloned and has no checkout for {}.",
+                    url, address.git_ref
+                ))
+            })
+            .to_state_err("Error resolving ref offline.".to_owned())?;
+        (commit, None)
+    };
+    let commit_short = str_last_n(&commit, 10);
+    let commit_path = checkouts_dir.join(commit_short).join(&encoded_paths);
+
+    // Whoever uses the checkout holds a shared lock on it, so that it is not removed while in use
+    let commit_lock_path = sibling_lock_path(&commit_path);
+    let checkout_lock = loop {
+        let checkout_lock =
+            lock_file_shared(&commit_lock_path).to_state_err("Failed to lock commit directory.")?;
+        if is_complete(&commit_path, |m| matches!(m, StoreMeta::Checkout(_)))? {
+            break checkout_lock;
+        }
+        drop(checkout_lock);
+
+        let _commit_lock =
+            lock_file(&commit_lock_path).to_state_err("Failed to lock commit directory.")?;
+        if is_complete(&commit_path, |m| matches!(m, StoreMeta::Checkout(_)))? {
+            continue;
+        }
+        if !offline {
+            git_fetch(&target_dir, &credentials)
+                .to_state_err("Error updating repository to ensure commit exists.".to_owned())?;
+        } else if !cloned {
+            return Err(GitError::Offline(format!(
+                "{} has not been cloned and commit {} is not checked out.",
+                url, commit
+            )))
+            .to_state_err("Error checking out commit offline.".to_owned());
+        }
+        materialize(&commit_path, |tmp_path| {
+            // Offline this only succeeds if all files of the commit were already fetched
+            checkout_paths(
+                &target_dir,
+                tmp_path,
+                &commit,
+                paths.clone(),
+                address.submodules,
+                address.lfs,
+                &credentials,
+            )
+            .to_state_err("Error checking out commit.".to_owned())?;
+            let meta = CheckoutMeta::new(
+                url.clone(),
+                name.clone(),
+                address.git_ref.clone(),
+                commit.clone(),
+                meta_paths.clone(),
+            );
+            write_meta(tmp_path, &StoreMeta::Checkout(meta))
+                .to_state_err("Failed to write metadata file!")
+        })?;
+    };
+
+    if let Some(verify) = &address.verify {
+        if offline && !cloned {
+            return Err(GitError::Unverified(format!(
+                "{} has not been cloned, so signatures cannot be checked offline.",
+                url
+            )))
+            .to_state_err("Error verifying signature.".to_owned());
+        }
+        let tag_name = tag.clone().unwrap_or_else(|| {
+            let git_ref = address.git_ref.strip_prefix("tag:");
+            let git_ref = git_ref.unwrap_or(&address.git_ref);
+            git_ref.trim_start_matches("refs/tags/").to_owned()
+        });
+        verify_signature(
+            &target_dir,
+            verify,
+            &tag_name,
+            &commit,
+            offline,
+            &credentials,
+        )
+        .to_state_err("Error verifying signature.".to_owned())?;
+    }
+
+    let resolved = ResolvedAddress::Git {
+        url,
+        git_ref: address.git_ref,
+        tag,
+        commit,
+        path: address.path.to_string(),
+        state_path: state_path.to_string(),
+    };
+
     Ok(State {
         name,
+        chain: vec![resolved],
+        checkout_locks: vec![checkout_lock],
         resolve_root: address.path.to_utf8_path(&commit_path),
         step: StateStep::Config,
         state_path,
@@ -303,6 +1036,12 @@ pub(crate) fn get_dir_from_git(
 }
 
 pub(crate) fn git_root_origin_url(path: &Utf8Path) -> Result<String, GitError> {
+    #[cfg(feature = "gix")]
+    if let Some(url) = try_native("get git root origin url", || {
+        gitoxide::git_root_origin_url(path)
+    }) {
+        return Ok(url);
+    }
     let args = vec!["config", "--get", "remote.origin.url"];
 
     let url = run_git(path, args, "get git root origin url")?;
@@ -311,3 +1050,112 @@ pub(crate) fn git_root_origin_url(path: &Utf8Path) -> Result<String, GitError> {
 
     Ok(url)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{checkout_paths, repo_clone, run_git, select_commit, ShaRef};
+    use crate::next::credentials::GitCredentials;
+
+    fn sha_ref(sha: &str, tag: &str) -> ShaRef {
+        ShaRef {
+            sha: sha.to_owned(),
+            tag: tag.to_owned(),
+        }
+    }
+
+    #[test]
+    fn select() {
+        assert_eq!(None, select_commit("abc", vec![]).unwrap());
+
+        let annotated = vec![
+            sha_ref("tagobject", "refs/tags/v1"),
+            sha_ref("commit1", "refs/tags/v1^{}"),
+        ];
+        assert_eq!(
+            Some("commit1".to_owned()),
+            select_commit("v1", annotated).unwrap()
+        );
+
+        let same = vec![
+            sha_ref("commit1", "refs/heads/main"),
+            sha_ref("commit1", "refs/tags/main"),
+            sha_ref("commit2", "refs/remotes/origin/main"),
+        ];
+        assert_eq!(
+            Some("commit1".to_owned()),
+            select_commit("main", same).unwrap()
+        );
+
+        let ambiguous = vec![
+            sha_ref("commit1", "refs/heads/main"),
+            sha_ref("tagobject", "refs/tags/main"),
+            sha_ref("commit2", "refs/tags/main^{}"),
+        ];
+        assert!(select_commit("main", ambiguous).is_err());
+    }
+
+    /// Checking out a treeless clone fetches from origin, so it needs the credentials the clone was made with.
+    #[cfg(unix)]
+    #[test]
+    fn checkout_authenticated() {
+        use camino::Utf8Path;
+        use std::{fs, os::unix::fs::PermissionsExt};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let tmp = Utf8Path::from_path(tmp.path()).unwrap();
+        let source = tmp.join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/file.txt"), "contents").unwrap();
+        for args in [
+            vec!["init", "-q"],
+            vec!["config", "uploadpack.allowFilter", "true"],
+            vec!["config", "uploadpack.allowAnySHA1InWant", "true"],
+            vec!["add", "-A"],
+            vec![
+                "-c",
+                "user.name=tidploy",
+                "-c",
+                "user.email=tidploy@example.com",
+                "commit",
+                "-q",
+                "-m",
+                "init",
+            ],
+        ] {
+            run_git(&source, args, "prepare source").unwrap();
+        }
+        let commit = run_git(&source, vec!["rev-parse", "HEAD"], "rev-parse").unwrap();
+        let commit = commit.trim();
+
+        // Stands in for the SSH command with a key, only it is able to reach the remote
+        let ssh = tmp.join("fake_ssh");
+        fs::write(&ssh, "#!/bin/sh\nshift\nexec sh -c \"$1\"\n").unwrap();
+        fs::set_permissions(&ssh, fs::Permissions::from_mode(0o700)).unwrap();
+        let mut credentials = GitCredentials::default();
+        credentials
+            .envs
+            .insert("GIT_SSH_COMMAND".to_owned(), ssh.to_string());
+        credentials
+            .envs
+            .insert("GIT_SSH_VARIANT".to_owned(), "simple".to_owned());
+
+        let url = format!("ssh://tidploy.invalid{}", source);
+        repo_clone(tmp, "repo", &url, &credentials).unwrap();
+        let target = tmp.join("checkout");
+        checkout_paths(
+            &tmp.join("repo"),
+            &target,
+            commit,
+            vec!["sub"],
+            false,
+            false,
+            &credentials,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "contents",
+            fs::read_to_string(target.join("sub/file.txt")).unwrap()
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Native implementations of the Git operations in [super::git], using gitoxide. Only compiled with the `gix`
+//! feature. Repositories cloned by the git CLI are partial clones, which gitoxide cannot yet work with, so
+//! for those every operation returns [GixError::Unsupported] and the CLI is used instead.
+
+use std::{error::Error, fs, sync::atomic::AtomicBool};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use gix::{
+    bstr::ByteSlice, objs::tree::EntryKind, progress::Discard, protocol::handshake::Ref,
+    remote::Direction, traverse::tree::Recorder, Repository,
+};
+use tracing::debug;
+
+use super::{errors::GixError, git::ShaRef};
+
+fn failed<E: Error + Send + Sync + 'static>(op: &'static str) -> impl FnOnce(E) -> GixError {
+    move |e| GixError::Failed {
+        op,
+        source: Box::new(e),
+    }
+}
+
+fn open(repo_dir: &Utf8Path) -> Result<Repository, GixError> {
+    let repo = gix::open(repo_dir).map_err(failed("open repository"))?;
+    let partial = repo
+        .config_snapshot()
+        .string("extensions.partialClone")
+        .is_some();
+    if partial {
+        return Err(GixError::Unsupported(format!(
+            "{} is a partial clone",
+            repo_dir
+        )));
+    }
+
+    Ok(repo)
+}
+
+/// Clones `repo_url` as a bare repository. Unlike the CLI this is a full clone, as gitoxide does not support
+/// partial clones.
+pub(crate) fn repo_clone(target_dir: &Utf8Path, repo_url: &str) -> Result<(), GixError> {
+    debug!("Cloning {} natively to {:?}", repo_url, target_dir);
+    let interrupt = AtomicBool::new(false);
+    let mut prepare =
+        gix::prepare_clone_bare(repo_url, target_dir).map_err(failed("prepare clone"))?;
+    prepare
+        .fetch_only(Discard, &interrupt)
+        .map_err(failed("clone"))?;
+
+    Ok(())
+}
+
+pub(crate) fn git_fetch(repo_dir: &Utf8Path) -> Result<(), GixError> {
+    let repo = open(repo_dir)?;
+    let interrupt = AtomicBool::new(false);
+    let remote = repo.find_remote("origin").map_err(failed("find remote"))?;
+    remote
+        .connect(Direction::Fetch)
+        .map_err(failed("connect"))?
+        .prepare_fetch(Discard, Default::default())
+        .map_err(failed("prepare fetch"))?
+        .receive(Discard, &interrupt)
+        .map_err(failed("fetch"))?;
+
+    Ok(())
+}
+
+/// Lists all refs of the remote like `git ls-remote origin`, including the peeled `^{}` entries of annotated
+/// tags.
+pub(crate) fn remote_refs(repo_dir: &Utf8Path) -> Result<Vec<ShaRef>, GixError> {
+    let repo = open(repo_dir)?;
+    let remote = repo.find_remote("origin").map_err(failed("find remote"))?;
+    let options = gix::remote::ref_map::Options {
+        prefix_from_spec_as_filter_on_remote: false,
+        ..Default::default()
+    };
+    let ref_map = remote
+        .connect(Direction::Fetch)
+        .map_err(failed("connect"))?
+        .ref_map(Discard, options)
+        .map_err(failed("list remote refs"))?;
+
+    let mut sha_refs = Vec::new();
+    for remote_ref in &ref_map.remote_refs {
+        if let Ref::Unborn { .. } = remote_ref {
+            continue;
+        }
+        let (name, target, peeled) = remote_ref.unpack();
+        let name = name.to_str_lossy();
+        if let Some(target) = target {
+            sha_refs.push(ShaRef {
+                sha: target.to_string(),
+                tag: name.to_string(),
+            });
+        }
+        if let Some(peeled) = peeled {
+            sha_refs.push(ShaRef {
+                sha: peeled.to_string(),
+                tag: format!("{}^{{}}", name),
+            });
+        }
+    }
+
+    Ok(sha_refs)
+}
+
+/// All refs in the repository, in the same format as `git show-ref --dereference`.
+pub(crate) fn local_refs(repo_dir: &Utf8Path) -> Result<Vec<ShaRef>, GixError> {
+    let repo = open(repo_dir)?;
+    let platform = repo.references().map_err(failed("list refs"))?;
+    let mut sha_refs = Vec::new();
+    for reference in platform.all().map_err(failed("list refs"))? {
+        let mut reference = reference.map_err(|source| GixError::Failed {
+            op: "read ref",
+            source,
+        })?;
+        let name = reference.name().as_bstr().to_str_lossy().into_owned();
+        let target = reference.target().try_id().map(ToOwned::to_owned);
+        let peeled = reference
+            .peel_to_id_in_place()
+            .map_err(failed("peel ref"))?
+            .detach();
+        if let Some(target) = target.filter(|t| *t != peeled) {
+            sha_refs.push(ShaRef {
+                sha: target.to_string(),
+                tag: name.clone(),
+            });
+            sha_refs.push(ShaRef {
+                sha: peeled.to_string(),
+                tag: format!("{}^{{}}", name),
+            });
+        } else {
+            sha_refs.push(ShaRef {
+                sha: peeled.to_string(),
+                tag: name,
+            });
+        }
+    }
+
+    Ok(sha_refs)
+}
+
+/// The full hash of `commit`, or None if the repository does not have it.
+pub(crate) fn local_commit(repo_dir: &Utf8Path, commit: &str) -> Result<Option<String>, GixError> {
+    let repo = open(repo_dir)?;
+    let spec = format!("{}^{{commit}}", commit);
+
+    Ok(repo
+        .rev_parse_single(spec.as_str())
+        .ok()
+        .map(|id| id.to_string()))
+}
+
+/// Whether `file` is part of a cone mode sparse checkout of `paths`. Like Git, this includes all files
+/// directly inside the root and inside any directory leading up to one of the paths.
+fn in_cone(file: &str, paths: &[&str]) -> bool {
+    let dir = file.rsplit_once('/').map(|(d, _)| d).unwrap_or("");
+    dir.is_empty()
+        || paths.iter().any(|p| {
+            !p.is_empty()
+                && (file.starts_with(&format!("{}/", p)) || p.starts_with(&format!("{}/", dir)))
+        })
+}
+
+/// Writes the files of `commit` that are in the cone of `paths` to `target_dir`, without any Git metadata.
+pub(crate) fn checkout_paths(
+    repo_dir: &Utf8Path,
+    target_dir: &Utf8Path,
+    commit: &str,
+    paths: &[&str],
+) -> Result<(), GixError> {
+    let repo = open(repo_dir)?;
+    let id = gix::ObjectId::from_hex(commit.as_bytes()).map_err(failed("parse commit"))?;
+    let tree = repo
+        .find_object(id)
+        .map_err(failed("find commit"))?
+        .try_into_commit()
+        .map_err(failed("read commit"))?
+        .tree()
+        .map_err(failed("read tree"))?;
+    let mut recorder = Recorder::default();
+    tree.traverse()
+        .breadthfirst(&mut recorder)
+        .map_err(failed("traverse tree"))?;
+
+    fs::create_dir_all(target_dir)?;
+    for entry in recorder.records {
+        let file = entry.filepath.to_str_lossy();
+        let kind = entry.mode.kind();
+        if kind == EntryKind::Tree || !in_cone(&file, paths) {
+            continue;
+        }
+        if kind == EntryKind::Commit {
+            return Err(GixError::Unsupported(format!("submodule at {}", file)));
+        }
+
+        let path = target_dir.join(Utf8PathBuf::from(file.as_ref()));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let blob = repo.find_object(entry.oid).map_err(failed("read blob"))?;
+        write_entry(&path, &blob.data, kind)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_entry(path: &Utf8Path, data: &[u8], kind: EntryKind) -> Result<(), GixError> {
+    use std::os::unix::{ffi::OsStrExt, fs::PermissionsExt};
+
+    if kind == EntryKind::Link {
+        std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(data), path)?;
+        return Ok(());
+    }
+    fs::write(path, data)?;
+    if kind == EntryKind::BlobExecutable {
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_entry(path: &Utf8Path, data: &[u8], kind: EntryKind) -> Result<(), GixError> {
+    if kind == EntryKind::Link {
+        return Err(GixError::Unsupported(format!("symbolic link at {}", path)));
+    }
+    fs::write(path, data)?;
+
+    Ok(())
+}
+
+pub(crate) fn git_root_dir(path: &Utf8Path) -> Result<String, GixError> {
+    let repo = gix::discover(path).map_err(failed("discover repository"))?;
+    let work_dir = repo
+        .work_dir()
+        .ok_or_else(|| GixError::Unsupported(format!("{} is a bare repository", path)))?;
+    let root = fs::canonicalize(work_dir)?;
+
+    root.into_os_string()
+        .into_string()
+        .map_err(|p| GixError::Unsupported(format!("non UTF-8 path {:?}", p)))
+}
+
+pub(crate) fn git_root_origin_url(path: &Utf8Path) -> Result<String, GixError> {
+    let repo = gix::discover(path).map_err(failed("discover repository"))?;
+    let url = repo
+        .config_snapshot()
+        .string("remote.origin.url")
+        .ok_or_else(|| GixError::Unsupported("no origin remote".to_owned()))?;
+
+    Ok(url.to_str_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::in_cone;
+
+    #[test]
+    fn cone() {
+        let paths = ["a/b"];
+        assert!(in_cone("f", &paths));
+        assert!(in_cone("a/g", &paths));
+        assert!(in_cone("a/b/h", &paths));
+        assert!(in_cone("a/b/c/i", &paths));
+        assert!(!in_cone("c/i", &paths));
+        assert!(!in_cone("a/bc/i", &paths));
+        assert!(!in_cone("a/g/i", &paths));
+        assert!(!in_cone("a/g", &[""]));
+    }
+}
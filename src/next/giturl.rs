@@ -0,0 +1,137 @@
+use super::errors::AddressError;
+
+/// A Git URL or local path to a repository, parsed only as far as needed to name and identify it. Supports
+/// URLs with a scheme (`https://`, `ssh://`, `git://`, `file://`, ...), scp-like URLs (`git@host:org/repo`)
+/// and local paths, including Windows paths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct GitUrl {
+    /// Lowercase host without user or port, None for local repositories.
+    host: Option<String>,
+    /// Path without trailing slashes or `.git` suffix, using forward slashes. Relative to the host for remote
+    /// repositories.
+    path: String,
+}
+
+impl GitUrl {
+    pub(crate) fn parse(url: &str) -> Result<Self, AddressError> {
+        let (host, path) = if let Some((scheme, rest)) = url.split_once("://") {
+            if scheme.eq_ignore_ascii_case("file") {
+                // file:///C:/repo has a path of /C:/repo
+                let path = match rest.strip_prefix('/') {
+                    Some(p) if has_drive(p) => p,
+                    _ => rest,
+                };
+                (None, path)
+            } else {
+                let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+                (Some(host_of(authority)), path)
+            }
+        } else {
+            match url.split_once(':') {
+                // A colon before any slash is an scp-like URL, unless it is a drive letter
+                Some((authority, path)) if !authority.contains(['/', '\\']) && !has_drive(url) => {
+                    (Some(host_of(authority)), path)
+                }
+                _ => (None, url),
+            }
+        };
+
+        let path = path.replace('\\', "/");
+        let path = path.trim_end_matches('/');
+        let path = path
+            .strip_suffix(".git")
+            .unwrap_or(path)
+            .trim_end_matches('/');
+        let path = match host {
+            Some(_) => path.trim_start_matches('/'),
+            None => path,
+        };
+        let parsed = Self {
+            host: host.filter(|h| !h.is_empty()),
+            path: path.to_owned(),
+        };
+        if parsed.name().is_empty() {
+            return Err(AddressError::RepoParse(url.to_owned()));
+        }
+
+        Ok(parsed)
+    }
+
+    /// The name of the repository, i.e. the last component of its path.
+    pub(crate) fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or_default()
+    }
+
+    /// Identifies the repository independent of how it is reached, so that e.g. the HTTPS and SSH URLs of a
+    /// repository are the same.
+    pub(crate) fn identity(&self) -> String {
+        match &self.host {
+            Some(host) => format!("{}/{}", host, self.path),
+            None => self.path.clone(),
+        }
+    }
+}
+
+fn has_drive(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 2
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && bytes.get(2).is_none_or(|c| *c == b'/' || *c == b'\\')
+}
+
+/// The host of an authority (`user@host:port`), in lowercase.
+fn host_of(authority: &str) -> String {
+    let host = authority.rsplit_once('@').map_or(authority, |(_, h)| h);
+    let host = match host.strip_prefix('[') {
+        // IPv6 addresses are enclosed in brackets, as they contain colons
+        Some(ipv6) => ipv6.split_once(']').map_or(ipv6, |(h, _)| h),
+        None => host.split_once(':').map_or(host, |(h, _)| h),
+    };
+
+    host.to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GitUrl;
+
+    fn parse(url: &str) -> (String, String) {
+        let parsed = GitUrl::parse(url).unwrap();
+        (parsed.name().to_owned(), parsed.identity())
+    }
+
+    #[test]
+    fn urls() {
+        let https = parse("https://github.com/org/my.app.git");
+        assert_eq!(
+            ("my.app".to_owned(), "github.com/org/my.app".to_owned()),
+            https
+        );
+        assert_eq!(https, parse("git@github.com:org/my.app.git"));
+        assert_eq!(https, parse("ssh://git@GitHub.com:22/org/my.app"));
+        assert_eq!(https, parse("https://user@github.com/org/my.app/"));
+
+        let other = parse("https://gitlab.com/org/my.app.git");
+        assert_ne!(https.1, other.1);
+
+        assert_eq!(
+            ("repo".to_owned(), "/srv/git/repo".to_owned()),
+            parse("/srv/git/repo.git")
+        );
+        assert_eq!(parse("/srv/git/repo"), parse("file:///srv/git/repo/"));
+        assert_eq!(
+            ("repo".to_owned(), "C:/git/repo".to_owned()),
+            parse("C:\\git\\repo")
+        );
+        assert_eq!(parse("C:\\git\\repo"), parse("file:///C:/git/repo"));
+        assert_eq!(
+            ("repo".to_owned(), "::1/org/repo".to_owned()),
+            parse("ssh://git@[::1]:2222/org/repo")
+        );
+        assert_eq!("host.local/repo", parse("host.local:repo").1);
+
+        assert!(GitUrl::parse("https://github.com/").is_err());
+        assert!(GitUrl::parse("").is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    time::{Duration, SystemTime},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{eyre, Context, Report};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::state::{ResolvedAddress, StateOptions};
+
+/// A single run of an entrypoint, as recorded in the history ledger.
+#[non_exhaustive]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Time the run started, in RFC 3339 format (UTC).
+    pub timestamp: String,
+    pub name: String,
+    /// Every address that was resolved for this run, starting with the one it was called with.
+    pub chain: Vec<ResolvedAddress>,
+    /// Commit of the last Git address in the chain, if there is one.
+    pub commit: Option<String>,
+    pub state_path: String,
+    pub executable: String,
+    /// None if the entrypoint was killed by a signal or could not be run at all.
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+    pub user: Option<String>,
+    /// Set if the run failed before the entrypoint could exit on its own.
+    pub error: Option<String>,
+}
+
+impl HistoryEntry {
+    pub(crate) fn new(
+        started: SystemTime,
+        name: String,
+        chain: Vec<ResolvedAddress>,
+        state_path: String,
+        executable: String,
+    ) -> Self {
+        let commit = chain.iter().rev().find_map(|a| match a {
+            ResolvedAddress::Git { commit, .. } => Some(commit.clone()),
+            ResolvedAddress::Local { .. } => None,
+        });
+        let user = env::var("USER").or_else(|_| env::var("USERNAME")).ok();
+
+        Self {
+            timestamp: humantime::format_rfc3339_seconds(started).to_string(),
+            name,
+            chain,
+            commit,
+            state_path,
+            executable,
+            exit_code: None,
+            duration_ms: 0,
+            user,
+            error: None,
+        }
+    }
+
+    fn started(&self) -> Option<SystemTime> {
+        humantime::parse_rfc3339(&self.timestamp).ok()
+    }
+
+    pub fn success(&self) -> bool {
+        self.error.is_none() && self.exit_code == Some(0)
+    }
+}
+
+fn history_path(store_dir: &Utf8Path) -> Utf8PathBuf {
+    store_dir.join("history.jsonl")
+}
+
+/// Appends the entry to the ledger as a single line, so that concurrent runs do not mix up their entries.
+fn append_entry(store_dir: &Utf8Path, entry: &HistoryEntry) -> io::Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+
+    fs::create_dir_all(store_dir)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(history_path(store_dir))?;
+    file.write_all(line.as_bytes())
+}
+
+/// Records the run in the ledger. A run is not considered failed just because it could not be recorded, so
+/// this only warns on failure.
+pub(crate) fn record_run(store_dir: &Utf8Path, entry: &HistoryEntry) {
+    debug!("Recording run in history: {:?}", entry);
+    if let Err(e) = append_entry(store_dir, entry) {
+        warn!("Failed to record run in history ledger! {}", e);
+    }
+}
+
+pub(crate) fn elapsed_ms(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct HistoryFilter {
+    pub(crate) name: Option<String>,
+    pub(crate) state_path: Option<String>,
+    pub(crate) since: Option<SystemTime>,
+    pub(crate) until: Option<SystemTime>,
+    pub(crate) failed: bool,
+    /// Only the most recent this many entries are returned.
+    pub(crate) limit: Option<usize>,
+}
+
+impl HistoryFilter {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        let started = entry.started();
+        self.name.as_ref().is_none_or(|n| &entry.name == n)
+            && self
+                .state_path
+                .as_ref()
+                .is_none_or(|p| &entry.state_path == p)
+            && self
+                .since
+                .is_none_or(|since| started.is_some_and(|s| s >= since))
+            && self
+                .until
+                .is_none_or(|until| started.is_some_and(|s| s < until))
+            && (!self.failed || !entry.success())
+    }
+}
+
+/// Parses either a date (`2024-05-07`) or a full RFC 3339 timestamp, both in UTC.
+pub(crate) fn parse_time(input: &str) -> Result<SystemTime, Report> {
+    let input = if input.len() == 10 {
+        format!("{}T00:00:00Z", input)
+    } else {
+        input.to_owned()
+    };
+
+    humantime::parse_rfc3339_weak(&input)
+        .map_err(|e| eyre!("Invalid time {}, use YYYY-MM-DD or RFC 3339! {}", input, e))
+}
+
+/// Reads the ledger and returns the matching entries from oldest to newest. Lines that cannot be parsed (for
+/// example when a write was interrupted) are skipped.
+pub(crate) fn history_command(
+    state_options: Option<StateOptions>,
+    filter: HistoryFilter,
+) -> Result<Vec<HistoryEntry>, Report> {
+    let store_dir = state_options.unwrap_or_default().store_dir;
+    let path = history_path(&store_dir);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = fs::read_to_string(&path).wrap_err("Failed to read history ledger.")?;
+
+    let mut entries: Vec<HistoryEntry> = contents
+        .lines()
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                debug!("Skipping invalid history line {:?}: {}", line, e);
+                None
+            }
+        })
+        .filter(|entry| filter.matches(entry))
+        .collect();
+
+    if let Some(limit) = filter.limit {
+        entries.drain(..entries.len().saturating_sub(limit));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::parse_time;
+
+    #[test]
+    fn parse_date_or_time() {
+        let day = UNIX_EPOCH + Duration::from_secs(1_715_040_000);
+        assert_eq!(day, parse_time("2024-05-07").unwrap());
+        assert_eq!(
+            day + Duration::from_secs(3600),
+            parse_time("2024-05-07T01:00:00Z").unwrap()
+        );
+        assert!(parse_time("last tuesday").is_err());
+    }
+}
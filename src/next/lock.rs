@@ -0,0 +1,224 @@
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    time::SystemTime,
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{eyre, Context, Report};
+use fs4::fs_std::FileExt;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// An advisory lock on a file, which is held until it is dropped. Other tidploy processes that lock the same
+/// path wait for it (or fail, see [try_lock_file]). As the lock belongs to the open file, it is also released
+/// if the process is killed.
+#[derive(Debug)]
+pub(crate) struct FileLock {
+    file: File,
+    path: Utf8PathBuf,
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        debug!("Releasing lock {:?}", self.path);
+    }
+}
+
+fn open_lock_file(path: &Utf8Path) -> io::Result<File> {
+    let open = || {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Never truncate here, as another process might be holding the lock and have written to it
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+    };
+    // The parent directory might have been removed by a prune right after it was created
+    match open() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => open(),
+        res => res,
+    }
+}
+
+/// Whether `file` is still the file at `path`. If not, it was removed (see [remove_lock_file]) while waiting
+/// for the lock, so the lock means nothing and has to be taken again.
+#[cfg(unix)]
+fn is_current(file: &File, path: &Utf8Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let locked = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok(locked.dev() == current.dev() && locked.ino() == current.ino()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Windows does not allow removing a file that is open, so it cannot have been removed.
+#[cfg(not(unix))]
+fn is_current(_file: &File, _path: &Utf8Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Opens the lock file and locks it using `lock`, which returns false if the lock is not available.
+fn take_lock(
+    path: &Utf8Path,
+    lock: impl Fn(&File) -> io::Result<bool>,
+) -> io::Result<Option<FileLock>> {
+    loop {
+        let file = open_lock_file(path)?;
+        if !lock(&file)? {
+            return Ok(None);
+        }
+        if is_current(&file, path)? {
+            return Ok(Some(FileLock {
+                file,
+                path: path.to_owned(),
+            }));
+        }
+        debug!(
+            "Lock {:?} was removed while waiting for it, locking again.",
+            path
+        );
+    }
+}
+
+/// Waits until the lock on `path` can be taken. The file is created if it does not yet exist.
+pub(crate) fn lock_file(path: &Utf8Path) -> io::Result<FileLock> {
+    debug!("Waiting for lock {:?}", path);
+    take_lock(path, |file| FileExt::lock_exclusive(file).map(|_| true))
+        .map(|lock| lock.expect("Waiting for a lock always takes it."))
+}
+
+/// Like [lock_file], but the lock can be held by several processes at once. It only keeps out processes that
+/// want to hold the lock by themselves.
+pub(crate) fn lock_file_shared(path: &Utf8Path) -> io::Result<FileLock> {
+    debug!("Waiting for shared lock {:?}", path);
+    take_lock(path, |file| FileExt::lock_shared(file).map(|_| true))
+        .map(|lock| lock.expect("Waiting for a lock always takes it."))
+}
+
+/// Takes the lock on `path` if no one else is holding it, otherwise returns None.
+pub(crate) fn try_lock_file(path: &Utf8Path) -> io::Result<Option<FileLock>> {
+    take_lock(path, FileExt::try_lock_exclusive)
+}
+
+/// Removes the lock file while holding the lock (which must not be shared), so that it does not stay behind
+/// once the directory it locks is gone. Processes that were waiting for it notice and lock the path again.
+pub(crate) fn remove_lock_file(lock: FileLock) -> io::Result<()> {
+    fs::remove_file(&lock.path)
+}
+
+/// Lock file placed next to `path`, so that a directory can be locked before it exists.
+pub(crate) fn sibling_lock_path(path: &Utf8Path) -> Utf8PathBuf {
+    let mut lock_path = path.as_str().to_owned();
+    lock_path.push_str(".lock");
+    Utf8PathBuf::from(lock_path)
+}
+
+/// Who is holding a deploy lock, written into the lock file so that others can see who they are waiting
+/// for.
+#[derive(Debug, Serialize, Deserialize)]
+struct LockOwner {
+    pid: u32,
+    user: Option<String>,
+    since: String,
+    command: String,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            user: env::var("USER").or_else(|_| env::var("USERNAME")).ok(),
+            since: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            command: env::args().collect::<Vec<_>>().join(" "),
+        }
+    }
+
+    fn describe(owner: Option<Self>) -> String {
+        match owner {
+            Some(o) => format!(
+                "{} (pid {}, user {}) since {}",
+                o.command,
+                o.pid,
+                o.user.as_deref().unwrap_or("unknown"),
+                o.since
+            ),
+            None => "an unknown process".to_owned(),
+        }
+    }
+}
+
+fn read_owner(path: &Utf8Path) -> Option<LockOwner> {
+    let mut contents = String::new();
+    File::open(path).ok()?.read_to_string(&mut contents).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+/// What to do when another process is already deploying the same target.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockWait {
+    Wait,
+    NoWait,
+}
+
+/// Lock on a deploy target (an address and state path, see [super::state::Address::key]), held for the
+/// whole deploy.
+pub(crate) fn deploy_lock_path(store_dir: &Utf8Path, address_key: &str) -> Utf8PathBuf {
+    store_dir
+        .join("locks")
+        .join(format!("{}.lock", address_key))
+}
+
+pub(crate) fn lock_deploy(path: &Utf8Path, wait: LockWait) -> Result<FileLock, Report> {
+    let lock = match try_lock_file(path).wrap_err("Failed to open deploy lock.")? {
+        Some(lock) => lock,
+        None => {
+            let owner = LockOwner::describe(read_owner(path));
+            if wait == LockWait::NoWait {
+                return Err(eyre!("Target is already being deployed by {}!", owner));
+            }
+            println!("Waiting for deploy by {} to finish...", owner);
+            lock_file(path).wrap_err("Failed to take deploy lock.")?
+        }
+    };
+
+    let owner = serde_json::to_string(&LockOwner::current())?;
+    let mut file = &lock.file;
+    file.set_len(0)
+        .and_then(|_| file.rewind())
+        .and_then(|_| file.write_all(owner.as_bytes()))
+        .wrap_err("Failed to write deploy lock owner.")?;
+
+    Ok(lock)
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+
+    use super::{lock_deploy, LockWait};
+
+    #[test]
+    fn deploy_lock_no_wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Utf8PathBuf::from_path_buf(dir.path().join("target.lock")).unwrap();
+
+        let lock = lock_deploy(&path, LockWait::Wait).unwrap();
+        let err = lock_deploy(&path, LockWait::NoWait).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&format!("pid {}", std::process::id())));
+
+        drop(lock);
+        assert!(lock_deploy(&path, LockWait::NoWait).is_ok());
+    }
+}
@@ -0,0 +1,335 @@
+use std::{fs, io::ErrorKind};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::Report;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument, warn};
+
+use super::{
+    errors::{
+        ConfigError, ConfigErrorKind, StateError, StateErrorKind, WrapConfigErr, WrapStateErr,
+    },
+    git::is_commit_hash,
+    state::{create_resolve_state, AddressIn, InferContext, ResolvedAddress, StateOptions},
+};
+
+/// Name of the lock file, which is placed next to the root config.
+pub const LOCK_FILENAME: &str = "tidploy.lock";
+
+const LOCK_VERSION: u32 = 1;
+
+const LOCK_HEADER: &str = "# Pins the commits of the Git addresses in the configs. Generated by tidploy, update it using \
+`tidploy next update`.\n";
+
+/// How the lock files are used while resolving, see [sync_lockfile].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum LockMode {
+    /// Use the pinned commits, resolving the refs of Git addresses that are not pinned. Nothing is written.
+    #[default]
+    Use,
+    /// Use the pinned commits and fail if any Git address is not pinned or any pin is no longer used.
+    Locked,
+    /// Ignore the pinned commits and pin every Git address to what it resolves to now. Only the lock file in a
+    /// repository is still used, as it cannot be written.
+    Update,
+}
+
+/// The commit a Git address resolved to when it was pinned.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct GitPin {
+    pub url: String,
+    pub git_ref: String,
+    pub commit: String,
+    /// The tag chosen when `git_ref` is a `semver:` or `latest-tag:` selector.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Lockfile {
+    version: u32,
+    #[serde(default, rename = "git")]
+    pub(crate) pins: Vec<GitPin>,
+}
+
+impl Lockfile {
+    /// Pins every Git address in `chain`.
+    fn from_chain<'a>(chain: impl IntoIterator<Item = &'a ResolvedAddress>) -> Self {
+        let mut pins: Vec<GitPin> = chain
+            .into_iter()
+            .filter_map(|a| match a {
+                ResolvedAddress::Git {
+                    url,
+                    git_ref,
+                    tag,
+                    commit,
+                    ..
+                } => Some(GitPin {
+                    url: url.clone(),
+                    git_ref: git_ref.clone(),
+                    commit: commit.clone(),
+                    tag: tag.clone(),
+                }),
+                ResolvedAddress::Local { .. } => None,
+            })
+            .collect();
+        pins.sort();
+        pins.dedup_by(|a, b| a.url == b.url && a.git_ref == b.git_ref);
+
+        Self {
+            version: LOCK_VERSION,
+            pins,
+        }
+    }
+
+    /// A lock file that only exists in memory, with just these pins.
+    pub(crate) fn from_pins(pins: Vec<GitPin>) -> Self {
+        Self {
+            version: LOCK_VERSION,
+            pins,
+        }
+    }
+
+    pub(crate) fn pin(&self, url: &str, git_ref: &str) -> Option<&GitPin> {
+        self.pins
+            .iter()
+            .find(|p| p.url == url && p.git_ref == git_ref)
+    }
+}
+
+pub(crate) fn read_lockfile(dir: &Utf8Path) -> Result<Option<Lockfile>, ConfigError> {
+    let path = dir.join(LOCK_FILENAME);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).to_config_err(format!("Failed to read lock file {:?}", path)),
+    };
+
+    let lock: Lockfile =
+        toml::from_str(&contents).to_config_err(format!("Failed to parse lock file {:?}", path))?;
+    if let Some(pin) = lock.pins.iter().find(|p| !is_commit_hash(&p.commit)) {
+        return Err(ConfigErrorKind::InvalidValue(format!(
+            "{:?} pinned for {} {} is not a commit hash.",
+            pin.commit, pin.url, pin.git_ref
+        )))
+        .to_config_err(format!("Failed to parse lock file {:?}", path));
+    }
+
+    Ok(Some(lock))
+}
+
+fn write_lockfile(dir: &Utf8Path, lock: &Lockfile) -> Result<(), ConfigError> {
+    let path = dir.join(LOCK_FILENAME);
+    let contents =
+        toml::to_string(lock).to_config_err(format!("Failed to serialize lock file {:?}", path))?;
+    fs::create_dir_all(dir).to_config_err(format!("Failed to create directory {:?}", dir))?;
+    fs::write(&path, format!("{}\n{}", LOCK_HEADER, contents))
+        .to_config_err(format!("Failed to write lock file {:?}", path))
+}
+
+/// Directory in the store with the lock file that pins the root Git address with this key, see [Address::key].
+/// A lock file in the repository cannot pin the commit it is part of, so it is kept here instead.
+///
+/// [Address::key]: super::state::Address::key
+pub(crate) fn root_lock_dir(store_dir: &Utf8Path, address_key: &str) -> Utf8PathBuf {
+    store_dir.join("pins").join(address_key)
+}
+
+fn lock_outdated<T>(reason: String) -> Result<T, StateError> {
+    Err(StateErrorKind::LockOutdated(reason))
+        .to_state_err("Refusing to resolve addresses that are not locked.")
+}
+
+fn is_unchanged(old: Option<&Lockfile>, new: &Lockfile) -> bool {
+    match old {
+        Some(old) => old.pins == new.pins,
+        None => new.pins.is_empty(),
+    }
+}
+
+/// Brings the lock file in `dir` in line with the addresses that were resolved, i.e. every Git address in the
+/// chain. It is only written in [LockMode::Update], while in [LockMode::Locked] it must already match. Returns
+/// the pins the addresses resolved to.
+pub(crate) fn sync_lockfile(
+    dir: &Utf8Path,
+    old: Option<Lockfile>,
+    chain: &[ResolvedAddress],
+    mode: LockMode,
+) -> Result<Vec<GitPin>, StateError> {
+    let new = Lockfile::from_chain(chain);
+    let unchanged = is_unchanged(old.as_ref(), &new);
+
+    match mode {
+        LockMode::Locked if !unchanged => {
+            return lock_outdated(format!(
+                "{} does not match the Git addresses in the configs, run `tidploy next update` to update it.",
+                dir.join(LOCK_FILENAME)
+            ));
+        }
+        LockMode::Update => {
+            debug!("Writing lock file in {}", dir);
+            write_lockfile(dir, &new).to_state_err("Failed to update lock file.")?;
+        }
+        LockMode::Use if !unchanged => debug!(
+            "Lock file in {} does not pin exactly the Git addresses in the configs.",
+            dir
+        ),
+        _ => {}
+    }
+
+    Ok(new.pins)
+}
+
+/// Like [sync_lockfile], for a chain that starts at a Git address. The lock file next to its config is part of
+/// the repository, so it is only checked and never written. Instead, the commit of the root address itself is
+/// pinned in the lock file in `root_dir` (see [root_lock_dir]), next to the pins of its other refs.
+pub(crate) fn sync_git_root_lockfile(
+    root_dir: &Utf8Path,
+    old_root: Option<Lockfile>,
+    repo_dir: &Utf8Path,
+    old_repo: Option<Lockfile>,
+    chain: &[ResolvedAddress],
+    mode: LockMode,
+) -> Result<Vec<GitPin>, StateError> {
+    let (root, rest) = chain.split_at(chain.len().min(1));
+    let new_root = Lockfile::from_chain(root);
+    let new_rest = Lockfile::from_chain(rest);
+
+    let root_unchanged = new_root.pins.iter().all(|pin| {
+        old_root
+            .as_ref()
+            .and_then(|old| old.pin(&pin.url, &pin.git_ref))
+            == Some(pin)
+    });
+    let repo_unchanged = is_unchanged(old_repo.as_ref(), &new_rest);
+
+    match mode {
+        LockMode::Locked if !root_unchanged => {
+            return lock_outdated(format!(
+                "The commit of the root Git address is not pinned in {}, run `tidploy next update` with the same \
+address to pin it.",
+                root_dir.join(LOCK_FILENAME)
+            ));
+        }
+        LockMode::Locked if !repo_unchanged => {
+            return lock_outdated(format!(
+                "{} does not match the Git addresses in the configs. It is part of the repository, so it can only be \
+updated there.",
+                repo_dir.join(LOCK_FILENAME)
+            ));
+        }
+        LockMode::Update => {
+            let mut lock = old_root.unwrap_or_default();
+            lock.version = LOCK_VERSION;
+            lock.pins.retain(|p| {
+                !new_root
+                    .pins
+                    .iter()
+                    .any(|n| n.url == p.url && n.git_ref == p.git_ref)
+            });
+            lock.pins.extend(new_root.pins.iter().cloned());
+            lock.pins.sort();
+            debug!("Writing lock file in {}", root_dir);
+            write_lockfile(root_dir, &lock).to_state_err("Failed to update lock file.")?;
+
+            if !repo_unchanged {
+                warn!(
+                    "{} does not match the Git addresses in the configs. It is part of the repository, so it can \
+only be updated there.",
+                    repo_dir.join(LOCK_FILENAME)
+                );
+            }
+        }
+        _ => {}
+    }
+
+    Ok(new_root.pins.into_iter().chain(new_rest.pins).collect())
+}
+
+/// Resolves every Git address in the configs again, ignoring the lock file, and pins them to the commits
+/// they resolve to now.
+#[instrument(name = "update", level = "debug", skip_all)]
+pub(crate) fn update_command(
+    addr_in: AddressIn,
+    git_infer: bool,
+    state_options: Option<StateOptions>,
+) -> Result<Vec<GitPin>, Report> {
+    let infer_ctx = if git_infer {
+        InferContext::Git
+    } else {
+        InferContext::Cwd
+    };
+    let state_options = StateOptions {
+        lock_mode: LockMode::Update,
+        ..state_options.unwrap_or_default()
+    };
+    let resolve_state = create_resolve_state(addr_in, infer_ctx, state_options)?;
+
+    Ok(resolve_state.pins)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use camino::Utf8Path;
+
+    use super::{read_lockfile, Lockfile, LOCK_FILENAME, LOCK_VERSION};
+    use crate::next::state::ResolvedAddress;
+
+    fn git(url: &str, git_ref: &str, commit: &str) -> ResolvedAddress {
+        ResolvedAddress::Git {
+            url: url.to_owned(),
+            git_ref: git_ref.to_owned(),
+            tag: None,
+            commit: commit.to_owned(),
+            path: String::new(),
+            state_path: String::new(),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let chain = [
+            git("https://b.example/b.git", "HEAD", "bbb"),
+            ResolvedAddress::Local {
+                path: "/tmp".to_owned(),
+                state_path: String::new(),
+            },
+            git("https://a.example/a.git", "v1", "aaa"),
+            git("https://b.example/b.git", "HEAD", "bbb"),
+        ];
+        let lock = Lockfile::from_chain(&chain);
+        assert_eq!(LOCK_VERSION, lock.version);
+        assert_eq!(2, lock.pins.len());
+        assert_eq!(
+            "aaa",
+            lock.pin("https://a.example/a.git", "v1").unwrap().commit
+        );
+        assert!(lock.pin("https://a.example/a.git", "HEAD").is_none());
+
+        let contents = toml::to_string(&lock).unwrap();
+        assert!(contents.contains("[[git]]"));
+        assert_eq!(lock, toml::from_str(&contents).unwrap());
+    }
+
+    #[test]
+    fn invalid_pin() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let lock = |commit: &str| {
+            format!(
+                "version = 1\n\n[[git]]\nurl = \"https://a.example/a.git\"\ngit_ref = \"HEAD\"\ncommit = {:?}\n",
+                commit
+            )
+        };
+
+        fs::write(dir.join(LOCK_FILENAME), lock("abc")).unwrap();
+        assert!(read_lockfile(dir).is_err());
+        fs::write(dir.join(LOCK_FILENAME), lock("../../main")).unwrap();
+        assert!(read_lockfile(dir).is_err());
+        fs::write(dir.join(LOCK_FILENAME), lock("0123456789abcdef")).unwrap();
+        assert_eq!(1, read_lockfile(dir).unwrap().unwrap().pins.len());
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{Context, Report};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::state::{address_key, AddressIn, InferContext, StateOptions};
+
+/// Directory that the logs of all runs of an address are kept in. `address_key` should come from
+/// [super::state::Address::key].
+pub(crate) fn log_dir(store_dir: &Utf8Path, address_key: &str) -> Utf8PathBuf {
+    store_dir.join("logs").join(address_key)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LogRotation {
+    /// Maximum number of log files kept for an address, the oldest ones are removed first.
+    pub(crate) max_files: usize,
+    /// Once a log file reaches this size in bytes, the run continues in a new file.
+    pub(crate) max_size: u64,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        Self {
+            max_files: 20,
+            max_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LogOptions {
+    pub(crate) dir: Utf8PathBuf,
+    pub(crate) rotation: LogRotation,
+}
+
+/// The log of a single run, which can consist of multiple files (parts) if it gets too large. Files are named
+/// `run_{unix millis}_{part}.log`.
+pub(crate) struct RunLog {
+    options: LogOptions,
+    run: u128,
+    part: u32,
+    file: File,
+    written: u64,
+}
+
+fn part_name(run: u128, part: u32) -> String {
+    format!("run_{:013}_{:03}.log", run, part)
+}
+
+/// A log file of the part `part` of the run started at `run` (in unix millis).
+struct LogFile {
+    run: u128,
+    part: u32,
+    path: Utf8PathBuf,
+}
+
+impl LogFile {
+    /// Returns None if the name is not that of a log file. The numbers are parsed, as a run can have more than
+    /// 999 parts, in which case sorting the names would not sort the parts.
+    fn parse(path: Utf8PathBuf) -> Option<Self> {
+        let name = path.file_name()?;
+        let (run, part) = name
+            .strip_prefix("run_")?
+            .strip_suffix(".log")?
+            .split_once('_')?;
+
+        Some(Self {
+            run: run.parse().ok()?,
+            part: part.parse().ok()?,
+            path,
+        })
+    }
+}
+
+impl RunLog {
+    pub(crate) fn create(options: LogOptions) -> io::Result<Self> {
+        fs::create_dir_all(&options.dir)?;
+        let mut run = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        // Another run might have started in the same millisecond, which then already has this id
+        let file = loop {
+            let path = options.dir.join(part_name(run, 0));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    debug!("Logging run to {:?}", path);
+                    break file;
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => run += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        prune(&options.dir, options.rotation.max_files, run)?;
+
+        Ok(Self {
+            options,
+            run,
+            part: 0,
+            file,
+            written: 0,
+        })
+    }
+
+    pub(crate) fn write(&mut self, output: &str) -> io::Result<()> {
+        let len = output.len() as u64;
+        if self.written > 0 && self.written + len > self.options.rotation.max_size {
+            self.part += 1;
+            self.file = File::create(self.options.dir.join(part_name(self.run, self.part)))?;
+            self.written = 0;
+            prune(&self.options.dir, self.options.rotation.max_files, self.run)?;
+        }
+
+        self.file.write_all(output.as_bytes())?;
+        self.written += len;
+
+        Ok(())
+    }
+}
+
+/// All log files in the directory, from oldest to newest.
+fn log_files(dir: &Utf8Path) -> io::Result<Vec<LogFile>> {
+    let mut files = Vec::new();
+    for entry in dir.read_dir_utf8()? {
+        files.extend(LogFile::parse(entry?.into_path()));
+    }
+    files.sort_by_key(|f| (f.run, f.part));
+
+    Ok(files)
+}
+
+/// Removes the oldest log files until at most `max_files` are left. The files of the run that is being logged
+/// (`active`) are never removed, so a single large run can take up more files than that.
+fn prune(dir: &Utf8Path, max_files: usize, active: u128) -> io::Result<()> {
+    let files = log_files(dir)?;
+    let excess = files.len().saturating_sub(max_files);
+    for file in files.iter().filter(|f| f.run != active).take(excess) {
+        debug!("Removing old log file {:?}", file.path);
+        fs::remove_file(&file.path)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the output of the latest run in `dir`, or None if nothing was logged yet. If `lines` is set, only
+/// that many lines from the end are returned.
+pub(crate) fn latest_run_log(dir: &Utf8Path, lines: Option<usize>) -> io::Result<Option<String>> {
+    if !dir.exists() {
+        return Ok(None);
+    }
+    let files = log_files(dir)?;
+    let Some(latest) = files.last().map(|f| f.run) else {
+        return Ok(None);
+    };
+
+    let mut output = String::new();
+    for file in files.iter().filter(|f| f.run == latest) {
+        output.push_str(&fs::read_to_string(&file.path)?);
+    }
+
+    if let Some(lines) = lines {
+        let all_lines: Vec<&str> = output.lines().collect();
+        let start = all_lines.len().saturating_sub(lines);
+        output = all_lines[start..].join("\n");
+    }
+
+    Ok(Some(output))
+}
+
+pub(crate) fn logs_command(
+    addr_in: AddressIn,
+    git_infer: bool,
+    state_options: Option<StateOptions>,
+    lines: Option<usize>,
+) -> Result<Option<String>, Report> {
+    let infer_ctx = if git_infer {
+        InferContext::Git
+    } else {
+        InferContext::Cwd
+    };
+    let key = address_key(addr_in, infer_ctx)?;
+    let store_dir = state_options.unwrap_or_default().store_dir;
+    let dir = log_dir(&store_dir, &key);
+    debug!("Reading latest run log from {:?}", dir);
+
+    latest_run_log(&dir, lines).wrap_err("Failed to read run logs.")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread, time::Duration};
+
+    use camino::Utf8PathBuf;
+
+    use super::{latest_run_log, log_files, part_name, LogOptions, LogRotation, RunLog};
+
+    #[test]
+    fn rotate_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8PathBuf::from_path_buf(dir.path().to_owned()).unwrap();
+        let options = LogOptions {
+            dir: dir.clone(),
+            rotation: LogRotation {
+                max_files: 2,
+                max_size: 8,
+            },
+        };
+
+        let mut run_log = RunLog::create(options.clone()).unwrap();
+        for line in ["first\n", "second\n", "third\n"] {
+            run_log.write(line).unwrap();
+        }
+
+        // The run that is being logged is kept in full, even though it has more parts than max_files
+        assert_eq!(3, log_files(&dir).unwrap().len());
+        assert_eq!(
+            "first\nsecond\nthird\n",
+            latest_run_log(&dir, None).unwrap().unwrap()
+        );
+
+        // Once the next run starts, the oldest files are removed
+        thread::sleep(Duration::from_millis(5));
+        let mut run_log = RunLog::create(options).unwrap();
+        run_log.write("fourth\n").unwrap();
+        assert_eq!(2, log_files(&dir).unwrap().len());
+        assert_eq!("fourth\n", latest_run_log(&dir, None).unwrap().unwrap());
+    }
+
+    #[test]
+    fn parts_sorted_by_number() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8PathBuf::from_path_buf(dir.path().to_owned()).unwrap();
+        for part in [999, 1000, 998] {
+            fs::write(dir.join(part_name(1, part)), format!("{}\n", part)).unwrap();
+        }
+        fs::write(dir.join("notes.txt"), "not a log").unwrap();
+
+        let parts: Vec<u32> = log_files(&dir).unwrap().iter().map(|f| f.part).collect();
+        assert_eq!(vec![998, 999, 1000], parts);
+        assert_eq!(
+            "998\n999\n1000\n",
+            latest_run_log(&dir, None).unwrap().unwrap()
+        );
+    }
+
+    #[test]
+    fn concurrent_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8PathBuf::from_path_buf(dir.path().to_owned()).unwrap();
+        let options = LogOptions {
+            dir: dir.clone(),
+            rotation: LogRotation {
+                max_files: 10,
+                max_size: 1024,
+            },
+        };
+
+        // Likely started in the same millisecond, but neither truncates the log of the other
+        let mut first = RunLog::create(options.clone()).unwrap();
+        let mut second = RunLog::create(options).unwrap();
+        first.write("first\n").unwrap();
+        second.write("second\n").unwrap();
+
+        assert_ne!(first.run, second.run);
+        assert_eq!(2, log_files(&dir).unwrap().len());
+        assert_eq!("second\n", latest_run_log(&dir, None).unwrap().unwrap());
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    time::SystemTime,
+};
+
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+
+/// Name of the metadata file at the root of every repository clone and checkout in the store. A directory
+/// without it is incomplete.
+pub const META_FILENAME: &str = "tidploy_meta.json";
+
+/// Version of the metadata schema, increased on every incompatible change.
+pub const META_VERSION: u32 = 1;
+
+/// Metadata of a directory in the store. Written as JSON, with `kind` set to either `repo` or `checkout`.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum StoreMeta {
+    Repo(RepoMeta),
+    Checkout(CheckoutMeta),
+}
+
+/// A clone of a repository, from which commits are checked out.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepoMeta {
+    pub version: u32,
+    pub url: String,
+    pub name: String,
+    /// RFC 3339 time (UTC) at which the directory was created.
+    pub created: String,
+    /// Version of tidploy that created the directory, None if created before metadata was versioned.
+    pub tidploy_version: Option<String>,
+}
+
+/// A checkout of a single commit of a repository, limited to the sparse paths.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckoutMeta {
+    pub version: u32,
+    pub url: String,
+    pub name: String,
+    /// The ref that was requested, which resolved to `commit`. None if created before metadata was versioned.
+    pub git_ref: Option<String>,
+    pub commit: String,
+    pub paths: Vec<String>,
+    /// RFC 3339 time (UTC) at which the directory was created.
+    pub created: String,
+    pub tidploy_version: Option<String>,
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+impl RepoMeta {
+    pub(crate) fn new(url: String, name: String) -> Self {
+        Self {
+            version: META_VERSION,
+            url,
+            name,
+            created: now(),
+            tidploy_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+        }
+    }
+}
+
+impl CheckoutMeta {
+    pub(crate) fn new(
+        url: String,
+        name: String,
+        git_ref: String,
+        commit: String,
+        paths: Vec<String>,
+    ) -> Self {
+        Self {
+            version: META_VERSION,
+            url,
+            name,
+            git_ref: Some(git_ref),
+            commit,
+            paths,
+            created: now(),
+            tidploy_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+        }
+    }
+
+    /// Time at which the checkout was created, if it can be parsed.
+    pub fn created_time(&self) -> Option<SystemTime> {
+        humantime::parse_rfc3339(&self.created).ok()
+    }
+}
+
+pub(crate) fn write_meta(dir: &Utf8Path, meta: &StoreMeta) -> io::Result<()> {
+    let contents = serde_json::to_string_pretty(meta)?;
+    fs::write(dir.join(META_FILENAME), contents)
+}
+
+/// Reads the metadata of a directory in the store, or None if it has none. Directories created by older
+/// versions of tidploy, which wrote `key:value` files named after the directory, can also be read.
+pub fn read_meta(dir: &Utf8Path) -> io::Result<Option<StoreMeta>> {
+    match fs::read_to_string(dir.join(META_FILENAME)) {
+        Ok(contents) => {
+            let meta = serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            Ok(Some(meta))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => read_legacy_meta(dir),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_legacy_meta(dir: &Utf8Path) -> io::Result<Option<StoreMeta>> {
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+    // Checkouts also contain the metadata of the repository they were copied from
+    let mut repo_meta = None;
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let is_checkout = file_name.starts_with("tidploy_deploy_meta_");
+        if !is_checkout && !file_name.starts_with("tidploy_repo_meta_") {
+            continue;
+        }
+
+        let contents = fs::read_to_string(entry.path())?;
+        let field = |key: &str| {
+            contents
+                .lines()
+                .filter_map(|l| l.split_once(':'))
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_owned())
+                .unwrap_or_default()
+        };
+        let created = entry
+            .metadata()?
+            .modified()
+            .map(|t| humantime::format_rfc3339_seconds(t).to_string())
+            .unwrap_or_default();
+
+        if is_checkout {
+            return Ok(Some(StoreMeta::Checkout(CheckoutMeta {
+                version: META_VERSION,
+                url: String::new(),
+                name: String::new(),
+                git_ref: None,
+                commit: field("commit"),
+                paths: vec![field("paths")],
+                created,
+                tidploy_version: None,
+            })));
+        }
+        repo_meta = Some(StoreMeta::Repo(RepoMeta {
+            version: META_VERSION,
+            url: field("url"),
+            name: field("name"),
+            created,
+            tidploy_version: None,
+        }));
+    }
+
+    Ok(repo_meta)
+}
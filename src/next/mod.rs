@@ -1,11 +1,24 @@
 pub mod api;
+pub(crate) mod cache;
 pub(crate) mod commands;
 pub(crate) mod config;
+pub(crate) mod credentials;
 pub(crate) mod errors;
 pub(crate) mod fs;
 pub(crate) mod git;
+#[cfg(feature = "gix")]
+pub(crate) mod gitoxide;
+pub(crate) mod giturl;
+pub(crate) mod history;
+pub(crate) mod lock;
+pub(crate) mod lockfile;
+pub(crate) mod logs;
+pub(crate) mod meta;
 pub(crate) mod process;
+pub(crate) mod refs;
+pub(crate) mod release;
 pub(crate) mod resolve;
 pub(crate) mod run;
 pub(crate) mod secrets;
 pub(crate) mod state;
+pub(crate) mod watch;
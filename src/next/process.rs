@@ -1,19 +1,39 @@
 use camino::{Utf8Path, Utf8PathBuf};
-use color_eyre::eyre::{Context, Report};
+use color_eyre::eyre::{eyre, Context, Report};
 use duct::{cmd, IntoExecutablePath};
+use portable_pty::{native_pty_system, CommandBuilder, PtySize};
+use serde::{Deserialize, Serialize};
 use std::ffi::OsStr;
 use std::fmt::Debug;
-use std::io::{stdout, Read, Write};
+use std::io::{self, stdout, Read, Write};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
 use std::process::ExitStatus;
-use std::str;
+use std::str::{self, Utf8Error};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, Once, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, io::BufReader};
-use tracing::{span, Level};
+use tracing::{debug, span, Level};
 
 use super::errors::{ProcessError, ProcessIOError};
+use super::logs::{LogOptions, RunLog};
 
 pub struct EntrypointOut {
     pub out: String,
     pub exit: ExitStatus,
+    /// Every attempt that was made to run the entrypoint, in order. `out` and `exit` belong to the last one.
+    pub attempts: Vec<AttemptOut>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AttemptOut {
+    pub exit: ExitStatus,
+    /// Whether the attempt was killed because it exceeded the timeout.
+    pub timed_out: bool,
+    pub duration: Duration,
 }
 
 pub(crate) fn process_out(bytes: Vec<u8>, info: String) -> Result<String, ProcessError> {
@@ -27,6 +47,7 @@ pub(crate) fn process_complete_output<P, E, S>(
     working_dir: P,
     program: E,
     args: Vec<S>,
+    envs: &HashMap<String, String>,
 ) -> Result<EntrypointOut, ProcessError>
 where
     // This is pretty bad...
@@ -34,8 +55,12 @@ where
     E: IntoExecutablePath + Debug + Clone,
     S: AsRef<OsStr> + Debug,
 {
-    let output = cmd(program.clone(), &args)
-        .dir(working_dir.clone().into())
+    let start = Instant::now();
+    let mut expression = cmd(program.clone(), &args).dir(working_dir.clone().into());
+    for (key, value) in envs {
+        expression = expression.env(key, value);
+    }
+    let output = expression
         .stderr_to_stdout()
         .stdout_capture()
         .unchecked()
@@ -53,72 +78,710 @@ where
     Ok(EntrypointOut {
         out,
         exit: output.status,
+        attempts: vec![AttemptOut {
+            exit: output.status,
+            timed_out: false,
+            duration: start.elapsed(),
+        }],
     })
 }
 
+/// How often and under which conditions a failed entrypoint is run again.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct RetryPolicy {
+    pub(crate) retries: u32,
+    pub(crate) delay: Duration,
+    /// Exit codes that warrant a retry. If empty, any failure is retried. Timeouts are always retried.
+    pub(crate) on_codes: Vec<i32>,
+}
+
+impl RetryPolicy {
+    fn should_retry(&self, attempt: &AttemptOut) -> bool {
+        if attempt.timed_out {
+            return true;
+        }
+        if attempt.exit.success() {
+            return false;
+        }
+
+        self.on_codes.is_empty()
+            || attempt
+                .exit
+                .code()
+                .is_some_and(|c| self.on_codes.contains(&c))
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct EntrypointOptions {
+    /// Useful mostly for testing, if set to None then the child process will just inherit the stdin of the
+    /// tidploy process.
+    pub(crate) input_bytes: Option<Vec<u8>>,
+    /// When set, the entrypoint is started in its own process group so that it and everything it started can
+    /// be killed once the timeout passes. As a consequence, it can no longer read from an interactive terminal.
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) retry: RetryPolicy,
+    /// If set, the entrypoint starts from an empty environment containing only these variables of the
+    /// tidploy process (if they exist), instead of the full environment.
+    pub(crate) inherit: Option<Vec<String>>,
+    /// Values that are masked in both the printed and the captured output.
+    pub(crate) redact: Vec<String>,
+    /// Attach the entrypoint to a pseudo-terminal instead of a pipe, so it behaves as if it were started from
+    /// an interactive shell.
+    pub(crate) pty: bool,
+    /// If set, the (redacted) output of every attempt is also written to a log file.
+    pub(crate) log: Option<LogOptions>,
+}
+
+const REDACTED: &[u8] = b"******";
+
+/// Masks secret values in a stream of output. Bytes that might be the start of a secret are held back until
+/// it is clear whether they are, so secrets split across reads are also masked.
+pub(crate) struct Redactor {
+    /// Longest first, so that a secret containing another secret is masked as a whole.
+    secrets: Vec<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl Redactor {
+    pub(crate) fn new(secrets: &[String]) -> Self {
+        let mut secrets: Vec<Vec<u8>> = secrets
+            .iter()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+        secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+
+        Self {
+            secrets,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Returns the part of the output that can be safely passed on.
+    pub(crate) fn push(&mut self, bytes: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(bytes);
+        self.drain(false)
+    }
+
+    /// Returns the remaining output, to be called once the stream has ended.
+    pub(crate) fn finish(&mut self) -> Vec<u8> {
+        self.drain(true)
+    }
+
+    fn drain(&mut self, finish: bool) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.pending.len());
+        let mut i = 0;
+        while i < self.pending.len() {
+            let rest = &self.pending[i..];
+            // The rest could still turn out to be a secret once more output arrives
+            if !finish
+                && self
+                    .secrets
+                    .iter()
+                    .any(|s| s.len() > rest.len() && s.starts_with(rest))
+            {
+                break;
+            }
+
+            match self.secrets.iter().find(|s| rest.starts_with(s)) {
+                Some(secret) => {
+                    out.extend_from_slice(REDACTED);
+                    i += secret.len();
+                }
+                None => {
+                    out.push(rest[0]);
+                    i += 1;
+                }
+            }
+        }
+        self.pending.drain(..i);
+
+        out
+    }
+}
+
+/// Decodes a stream of UTF-8 bytes, holding back a character that is split across reads.
+#[derive(Default)]
+struct Utf8Decoder {
+    carry: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    fn decode(&mut self, bytes: &[u8], finish: bool) -> Result<String, Utf8Error> {
+        self.carry.extend_from_slice(bytes);
+        let valid_up_to = match str::from_utf8(&self.carry) {
+            Ok(_) => self.carry.len(),
+            // If there is no error length, the input ended in the middle of a character
+            Err(e) if e.error_len().is_none() && !finish => e.valid_up_to(),
+            Err(e) => return Err(e),
+        };
+        let rest = self.carry.split_off(valid_up_to);
+        let decoded = String::from_utf8(std::mem::replace(&mut self.carry, rest))
+            .expect("Bytes were validated as UTF-8.");
+
+        Ok(decoded)
+    }
+}
+
+/// Kills the process group led by `pid`, which includes any processes the entrypoint started itself.
+#[cfg(unix)]
+fn kill_tree(pid: u32) -> io::Result<()> {
+    let pgid = i32::try_from(pid).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // A negative pid signals every process in the group
+    let res = unsafe { libc::kill(-pgid, libc::SIGKILL) };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(windows)]
+fn kill_tree(pid: u32) -> io::Result<()> {
+    std::process::Command::new("taskkill")
+        .args(["/F", "/T", "/PID", &pid.to_string()])
+        .output()
+        .map(|_| ())
+}
+
+/// Determines the environment the entrypoint starts from, before any variables set by tidploy are added.
+fn base_envs(inherit: Option<&[String]>) -> HashMap<String, String> {
+    match inherit {
+        Some(names) => names
+            .iter()
+            .filter_map(|name| std::env::var(name).ok().map(|v| (name.clone(), v)))
+            .collect(),
+        None => std::env::vars().collect(),
+    }
+}
+
 /// Runs the entrypoint, sending the entrypoint's stdout and stderr to stdout. It adds the provided envs to
-/// the envs of the tidploy process. `input_bytes` is useful mostly for testing, if set to None then the
-/// child process will just inherit the stdin of the tidploy process.
+/// the envs of the tidploy process (or only the allowed ones, see [EntrypointOptions]). If the entrypoint
+/// fails or times out, it is run again according to the retry policy in `options`. The returned output is
+/// that of the final attempt.
 pub(crate) fn run_entrypoint(
     working_dir: &Utf8Path,
     entrypoint: &Utf8Path,
     envs: HashMap<String, String>,
-    input_bytes: Option<Vec<u8>>,
+    options: EntrypointOptions,
 ) -> Result<EntrypointOut, Report> {
     println!(
         "Running {:?} in working dir {:?}!",
         &entrypoint, &working_dir
     );
     // Use parent process env variables as base
-    let mut combined_envs: HashMap<_, _> = std::env::vars().collect();
+    let mut combined_envs = base_envs(options.inherit.as_deref());
+    debug!(
+        "Entrypoint starts with {} inherited env variables.",
+        combined_envs.len()
+    );
     combined_envs.extend(envs);
 
+    let mut run_log = options
+        .log
+        .clone()
+        .map(RunLog::create)
+        .transpose()
+        .wrap_err("Failed to create run log!")?;
+
+    let max_attempts = options.retry.retries.saturating_add(1);
+    let mut attempts = Vec::with_capacity(1);
+    let mut attempt_num = 1;
+    loop {
+        if let Some(run_log) = run_log.as_mut().filter(|_| attempt_num > 1) {
+            run_log
+                .write(&format!(
+                    "\n--- attempt {}/{} ---\n",
+                    attempt_num, max_attempts
+                ))
+                .wrap_err("Error writing to run log!")?;
+        }
+        let (out, attempt) = run_attempt(
+            working_dir,
+            entrypoint,
+            &combined_envs,
+            &options,
+            run_log.as_mut(),
+        )?;
+        if attempt.timed_out {
+            println!(
+                "Entrypoint timed out after {:?} (attempt {}/{})!",
+                attempt.duration, attempt_num, max_attempts
+            );
+        }
+        let retry = attempt_num < max_attempts && options.retry.should_retry(&attempt);
+        attempts.push(attempt);
+
+        if !retry {
+            let exit = attempts
+                .last()
+                .map(|a| a.exit)
+                .unwrap_or(ExitStatus::default());
+            return Ok(EntrypointOut {
+                out,
+                exit,
+                attempts,
+            });
+        }
+
+        debug!(
+            "Attempt {} failed, retrying in {:?}.",
+            attempt_num, options.retry.delay
+        );
+        println!(
+            "Entrypoint failed (attempt {}/{}), retrying...",
+            attempt_num, max_attempts
+        );
+        thread::sleep(options.retry.delay);
+        attempt_num += 1;
+    }
+}
+
+fn run_attempt(
+    working_dir: &Utf8Path,
+    entrypoint: &Utf8Path,
+    combined_envs: &HashMap<String, String>,
+    options: &EntrypointOptions,
+    run_log: Option<&mut RunLog>,
+) -> Result<(String, AttemptOut), Report> {
+    if options.pty {
+        return run_attempt_pty(working_dir, entrypoint, combined_envs, options, run_log);
+    }
+
+    let start = Instant::now();
+    let timeout = options.timeout;
+
     let cmd_expr = cmd(entrypoint.as_std_path(), Vec::<String>::new())
         .dir(working_dir)
-        .full_env(&combined_envs)
+        .full_env(combined_envs)
         .stderr_to_stdout()
         .unchecked();
 
     // This is useful for testing input
-    let cmd_expr = if let Some(input_bytes) = input_bytes {
-        cmd_expr.stdin_bytes(input_bytes)
+    let cmd_expr = if let Some(input_bytes) = &options.input_bytes {
+        cmd_expr.stdin_bytes(input_bytes.clone())
+    } else {
+        cmd_expr
+    };
+
+    // Only with a timeout do we need to be able to kill everything the entrypoint started
+    #[cfg(unix)]
+    let cmd_expr = if timeout.is_some() {
+        cmd_expr.before_spawn(|c| {
+            c.process_group(0);
+            Ok(())
+        })
     } else {
         cmd_expr
     };
 
     let reader = cmd_expr.reader()?;
 
+    let watchdog = Watchdog::start(timeout, reader.pids().first().copied());
+
     let entry_span = span!(Level::DEBUG, "entrypoint", path = entrypoint.as_str());
     let _enter = entry_span.enter();
 
+    let out = pass_output(&reader, &options.redact, run_log);
+    let timed_out = watchdog.stop();
+    let out = out?;
+
+    let maybe_output = reader
+        .try_wait()
+        .wrap_err("Error trying to get reader exit status!")?;
+    let exit = maybe_output
+        .map(|out| out.status)
+        .unwrap_or(ExitStatus::default());
+
+    let attempt = AttemptOut {
+        exit,
+        timed_out,
+        duration: start.elapsed(),
+    };
+
+    Ok((out, attempt))
+}
+
+/// Like [run_attempt], but with the entrypoint attached to a pseudo-terminal. Its output is still captured,
+/// but note that the terminal turns every newline into "\r\n".
+fn run_attempt_pty(
+    working_dir: &Utf8Path,
+    entrypoint: &Utf8Path,
+    combined_envs: &HashMap<String, String>,
+    options: &EntrypointOptions,
+    run_log: Option<&mut RunLog>,
+) -> Result<(String, AttemptOut), Report> {
+    let start = Instant::now();
+
+    let pair = native_pty_system()
+        .openpty(terminal_size())
+        .map_err(|e| eyre!("Failed to open pseudo-terminal! {}", e))?;
+
+    let mut command = CommandBuilder::new(entrypoint.as_std_path());
+    command.cwd(working_dir.as_std_path());
+    command.env_clear();
+    for (k, v) in combined_envs {
+        command.env(k, v);
+    }
+
+    let mut child = pair
+        .slave
+        .spawn_command(command)
+        .map_err(|e| eyre!("Failed to start entrypoint in pseudo-terminal! {}", e))?;
+    // Only the child should hold on to the slave side, otherwise we never see the end of the output
+    drop(pair.slave);
+
+    let reader = pair
+        .master
+        .try_clone_reader()
+        .map_err(|e| eyre!("Failed to read from pseudo-terminal! {}", e))?;
+    let mut writer = pair
+        .master
+        .take_writer()
+        .map_err(|e| eyre!("Failed to write to pseudo-terminal! {}", e))?;
+
+    // Dropping the writer signals the end of the input to the entrypoint
+    let (_raw_mode, _stdin_forward) = match &options.input_bytes {
+        Some(input_bytes) => {
+            writer
+                .write_all(input_bytes)
+                .wrap_err("Error writing input to pseudo-terminal!")?;
+            drop(writer);
+            (None, None)
+        }
+        None => (RawMode::enable(), Some(StdinForward::start(writer))),
+    };
+
+    // The child leads its own session, so this also kills everything it started
+    let watchdog = Watchdog::start(options.timeout, child.process_id());
+
+    let entry_span = span!(
+        Level::DEBUG,
+        "entrypoint",
+        path = entrypoint.as_str(),
+        pty = true
+    );
+    let _enter = entry_span.enter();
+
+    let out = pass_output(reader, &options.redact, run_log);
+    let timed_out = watchdog.stop();
+    let out = out?;
+
+    let status = child
+        .wait()
+        .wrap_err("Error waiting for entrypoint in pseudo-terminal!")?;
+
+    let attempt = AttemptOut {
+        exit: exit_status_from_code(status.exit_code()),
+        timed_out,
+        duration: start.elapsed(),
+    };
+
+    Ok((out, attempt))
+}
+
+#[cfg(unix)]
+fn exit_status_from_code(code: u32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+
+    // The raw value is a wait status, which stores the exit code in the second byte
+    ExitStatus::from_raw((code as i32) << 8)
+}
+
+#[cfg(windows)]
+fn exit_status_from_code(code: u32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+
+    ExitStatus::from_raw(code)
+}
+
+/// The size of the terminal tidploy runs in, so the entrypoint can lay out its output the same way.
+fn terminal_size() -> PtySize {
+    let mut size = PtySize {
+        rows: 24,
+        cols: 80,
+        pixel_width: 0,
+        pixel_height: 0,
+    };
+
+    #[cfg(unix)]
+    {
+        // SAFETY: winsize is plain data and ioctl only writes to it
+        let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+        let res = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) };
+        if res == 0 && ws.ws_row > 0 && ws.ws_col > 0 {
+            size.rows = ws.ws_row;
+            size.cols = ws.ws_col;
+        }
+    }
+
+    size
+}
+
+/// While this exists, the terminal of the tidploy process is in raw mode, so that key presses are passed on to
+/// the pseudo-terminal directly instead of being echoed and buffered by both terminals.
+struct RawMode {
+    #[cfg(unix)]
+    original: libc::termios,
+}
+
+impl RawMode {
+    #[cfg(unix)]
+    fn enable() -> Option<Self> {
+        // SAFETY: termios is plain data and is only passed to the terminal functions that fill it
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1 {
+                return None;
+            }
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return None;
+            }
+            let original = termios;
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+                return None;
+            }
+
+            Some(Self { original })
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn enable() -> Option<Self> {
+        None
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings we read in enable
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Where the input read from the stdin of the tidploy process goes: the pseudo-terminal of the entrypoint that
+/// is running, or if there is none, a buffer that is passed on to the next one.
+struct StdinTarget {
+    writer: Option<Box<dyn Write + Send>>,
+    pending: Vec<u8>,
+}
+
+static STDIN_TARGET: Mutex<StdinTarget> = Mutex::new(StdinTarget {
+    writer: None,
+    pending: Vec::new(),
+});
+
+static STDIN_THREAD: Once = Once::new();
+
+fn stdin_target() -> MutexGuard<'static, StdinTarget> {
+    STDIN_TARGET.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Forwards stdin to the pseudo-terminal of an entrypoint while this exists. A thread blocked on reading stdin
+/// cannot be stopped, so all entrypoints (across retries and restarts) share a single forwarding thread that
+/// is pointed at the current one. Otherwise stale threads would take input meant for the current entrypoint.
+struct StdinForward;
+
+impl StdinForward {
+    fn start(mut writer: Box<dyn Write + Send>) -> Self {
+        let mut target = stdin_target();
+        let pending = std::mem::take(&mut target.pending);
+        if let Err(e) = writer.write_all(&pending).and_then(|_| writer.flush()) {
+            debug!(
+                "Failed to pass on earlier input to pseudo-terminal: {:?}",
+                e
+            );
+        }
+        target.writer = Some(writer);
+        drop(target);
+
+        STDIN_THREAD.call_once(|| {
+            thread::spawn(|| {
+                let mut stdin = io::stdin();
+                let mut buffer = [0; 1024];
+                loop {
+                    let bytes_read = match stdin.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(bytes_read) => bytes_read,
+                    };
+                    let mut target = stdin_target();
+                    let bytes = &buffer[..bytes_read];
+                    match target.writer.as_mut() {
+                        Some(writer) => {
+                            if let Err(e) = writer.write_all(bytes).and_then(|_| writer.flush()) {
+                                debug!("Failed to forward input to pseudo-terminal: {:?}", e);
+                            }
+                        }
+                        None => target.pending.extend_from_slice(bytes),
+                    }
+                }
+                // Dropping the writer makes the terminal echo a newline, so once our stdin ends (for example
+                // when it is not a terminal) the writer is only dropped after the entrypoint exits
+            });
+        });
+
+        Self
+    }
+}
+
+impl Drop for StdinForward {
+    fn drop(&mut self) {
+        let writer = stdin_target().writer.take();
+        drop(writer);
+    }
+}
+
+/// Kills the process tree of the entrypoint if it is still running once the timeout passes.
+struct Watchdog {
+    done_tx: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    fn start(timeout: Option<Duration>, pid: Option<u32>) -> Self {
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let (timeout, pid) = match (timeout, pid) {
+            (Some(timeout), Some(pid)) => (timeout, pid),
+            _ => {
+                return Self {
+                    done_tx: None,
+                    handle: None,
+                    timed_out,
+                }
+            }
+        };
+
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let timed_out_flag = timed_out.clone();
+        let handle = thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(timeout) {
+                timed_out_flag.store(true, Ordering::SeqCst);
+                if let Err(e) = kill_tree(pid) {
+                    debug!("Failed to kill entrypoint process tree: {:?}", e);
+                }
+            }
+        });
+
+        Self {
+            done_tx: Some(done_tx),
+            handle: Some(handle),
+            timed_out,
+        }
+    }
+
+    /// Stops the watchdog if it is still waiting and returns whether the timeout was reached.
+    fn stop(mut self) -> bool {
+        drop(self.done_tx.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+
+        self.timed_out.load(Ordering::SeqCst)
+    }
+}
+
+/// Prints the output of the entrypoint as it arrives (also writing it to the run log, if there is one) and
+/// returns all of it once the stream ends.
+fn pass_output(
+    reader: impl Read,
+    redact: &[String],
+    mut run_log: Option<&mut RunLog>,
+) -> Result<String, Report> {
     let mut out: String = String::with_capacity(128);
 
+    let mut redactor = Redactor::new(redact);
+    let mut decoder = Utf8Decoder::default();
+
     let mut reader = BufReader::new(reader);
     let mut buffer_out = [0; 32];
     loop {
         let bytes_read_out = reader
             .read(&mut buffer_out)
             .wrap_err("Error reading stdout bytes!")?;
+        let finished = bytes_read_out == 0;
 
-        if bytes_read_out > 0 {
-            let string_buf = str::from_utf8(&buffer_out[..bytes_read_out])
-                .wrap_err("Error converting stdout bytes to UTF-8!")?;
-            print!("{}", string_buf);
-            // This flush is important in case the script only writes a few characters
-            // Like in the case of a progress bar or spinner
-            let _ = stdout().flush();
-            out.push_str(string_buf);
+        let bytes = if finished {
+            redactor.finish()
         } else {
+            redactor.push(&buffer_out[..bytes_read_out])
+        };
+        let string_buf = decoder
+            .decode(&bytes, finished)
+            .wrap_err("Error converting stdout bytes to UTF-8!")?;
+        print!("{}", string_buf);
+        // This flush is important in case the script only writes a few characters
+        // Like in the case of a progress bar or spinner
+        let _ = stdout().flush();
+        if let Some(run_log) = run_log.as_mut() {
+            run_log
+                .write(&string_buf)
+                .wrap_err("Error writing to run log!")?;
+        }
+        out.push_str(&string_buf);
+
+        if finished {
             break;
         }
     }
-    let inner_reader = reader.into_inner();
-    let maybe_output = inner_reader
-        .try_wait()
-        .wrap_err("Error trying to get reader exit status!")?;
-    let exit = maybe_output
-        .map(|out| out.status)
-        .unwrap_or(ExitStatus::default());
 
-    Ok(EntrypointOut { out, exit })
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use camino::Utf8Path;
+
+    use super::{run_entrypoint, EntrypointOptions, Redactor};
+
+    fn redact_chunks(secrets: &[&str], chunks: &[&str]) -> String {
+        let secrets: Vec<String> = secrets.iter().map(|s| s.to_string()).collect();
+        let mut redactor = Redactor::new(&secrets);
+        let mut out: Vec<u8> = chunks
+            .iter()
+            .flat_map(|c| redactor.push(c.as_bytes()))
+            .collect();
+        out.extend(redactor.finish());
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn redact_split() {
+        assert_eq!(
+            "token: ******!",
+            redact_chunks(&["hunter2"], &["token: hun", "te", "r2!"])
+        );
+        assert_eq!("hunt", redact_chunks(&["hunter2"], &["hu", "nt"]));
+        assert_eq!(
+            "****** and ******",
+            redact_chunks(&["abc", "abcdef"], &["abcdef and a", "bc"])
+        );
+        assert_eq!("nothing", redact_chunks(&[""], &["nothing"]));
+    }
+
+    #[test]
+    fn redact_entrypoint() {
+        let envs = HashMap::from([("TIDPLOY_SOME_SECRET".to_owned(), "abc".to_owned())]);
+        let options = EntrypointOptions {
+            redact: vec!["abc".to_owned()],
+            ..Default::default()
+        };
+        let out = run_entrypoint(
+            Utf8Path::new("."),
+            Utf8Path::new("examples/run/example_secret.sh"),
+            envs,
+            options,
+        )
+        .unwrap();
+
+        assert_eq!("******", out.out.trim());
+    }
 }
@@ -0,0 +1,143 @@
+use semver::{Version, VersionReq};
+
+use super::errors::GitError;
+
+/// How the `git_ref` of a Git address is resolved to a commit.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum RefSelector {
+    /// A ref or commit, matched like `git ls-remote` does. `branch:<name>` and `tag:<name>` are parsed to the
+    /// full ref, which only matches itself.
+    Exact(String),
+    /// `commit:<hash>`, a commit that is not looked up among the refs.
+    Commit(String),
+    /// `semver:<requirement>`, the highest tag that is a version matching the requirement.
+    Semver(VersionReq),
+    /// `latest-tag:<glob>`, the highest version among the tags that match the glob.
+    LatestTag(String),
+}
+
+impl RefSelector {
+    pub(crate) fn parse(git_ref: &str) -> Result<Self, GitError> {
+        if let Some(req) = git_ref.strip_prefix("semver:") {
+            let req = VersionReq::parse(req).map_err(|e| {
+                GitError::InvalidRef(format!("{} is not a valid semver requirement: {}", req, e))
+            })?;
+            Ok(Self::Semver(req))
+        } else if let Some(glob) = git_ref.strip_prefix("latest-tag:") {
+            Ok(Self::LatestTag(glob.to_owned()))
+        } else if let Some(branch) = git_ref.strip_prefix("branch:") {
+            Ok(Self::Exact(format!("refs/heads/{}", branch)))
+        } else if let Some(tag) = git_ref.strip_prefix("tag:") {
+            Ok(Self::Exact(format!("refs/tags/{}", tag)))
+        } else if let Some(commit) = git_ref.strip_prefix("commit:") {
+            Ok(Self::Commit(commit.to_owned()))
+        } else {
+            Ok(Self::Exact(git_ref.to_owned()))
+        }
+    }
+
+    /// Chooses the tag with the highest version that satisfies the selector, from tag names without the
+    /// `refs/tags/` prefix. Anything before the first digit (like a `v`) is ignored when parsing versions and
+    /// tags that are not versions are skipped.
+    pub(crate) fn select_tag<'a>(
+        &self,
+        tags: impl IntoIterator<Item = &'a str>,
+    ) -> Option<&'a str> {
+        tags.into_iter()
+            .filter_map(|tag| {
+                let version = tag.trim_start_matches(|c: char| !c.is_ascii_digit());
+                let version = Version::parse(version).ok()?;
+                let matches = match self {
+                    Self::Exact(_) | Self::Commit(_) => false,
+                    Self::Semver(req) => req.matches(&version),
+                    Self::LatestTag(glob) => glob_matches(glob, tag),
+                };
+                matches.then_some((version, tag))
+            })
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, tag)| tag)
+    }
+}
+
+/// Matches `name` against a glob in which `*` matches any sequence of characters and `?` any single one.
+fn glob_matches(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Position in the glob of the last `*` and the position in the name it was tried at
+    let (mut g, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if g < glob.len() && (glob[g] == '?' || glob[g] == name[n]) {
+            g += 1;
+            n += 1;
+        } else if g < glob.len() && glob[g] == '*' {
+            star = Some((g, n));
+            g += 1;
+        } else if let Some((star_g, star_n)) = star {
+            // Let the `*` match one more character
+            g = star_g + 1;
+            n = star_n + 1;
+            star = Some((star_g, n));
+        } else {
+            return false;
+        }
+    }
+
+    glob[g..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_matches, RefSelector};
+
+    #[test]
+    fn select_tags() {
+        let tags = [
+            "v1.3.9",
+            "v1.4.0",
+            "v1.4.2",
+            "v1.5.0-rc.1",
+            "v2.0.0",
+            "api-v3.0.0",
+            "main",
+        ];
+
+        let selector = RefSelector::parse("semver:^1.4").unwrap();
+        assert_eq!(Some("v1.4.2"), selector.select_tag(tags));
+        let selector = RefSelector::parse("latest-tag:v*").unwrap();
+        assert_eq!(Some("v2.0.0"), selector.select_tag(tags));
+        let selector = RefSelector::parse("latest-tag:v1.?.*").unwrap();
+        assert_eq!(Some("v1.5.0-rc.1"), selector.select_tag(tags));
+        let selector = RefSelector::parse("latest-tag:api-v*").unwrap();
+        assert_eq!(Some("api-v3.0.0"), selector.select_tag(tags));
+        let selector = RefSelector::parse("semver:^4").unwrap();
+        assert_eq!(None, selector.select_tag(tags));
+
+        assert_eq!(
+            RefSelector::Exact("main".to_owned()),
+            RefSelector::parse("main").unwrap()
+        );
+        assert_eq!(
+            RefSelector::Exact("refs/heads/main".to_owned()),
+            RefSelector::parse("branch:main").unwrap()
+        );
+        assert_eq!(
+            RefSelector::Exact("refs/tags/main".to_owned()),
+            RefSelector::parse("tag:main").unwrap()
+        );
+        assert_eq!(
+            RefSelector::Commit("abc123".to_owned()),
+            RefSelector::parse("commit:abc123").unwrap()
+        );
+        assert!(RefSelector::parse("semver:not a version").is_err());
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_matches("v*", "v1.0.0"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(!glob_matches("v*", "api-v1"));
+        assert!(!glob_matches("v?", "v10"));
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    fs, io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{eyre, Context, Report};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::{cache::checkout_root, resolve::RunResolved, state::ResolvedAddress};
+
+/// Everything needed to run a release again exactly as it was run when it was deployed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ReleaseRecord {
+    pub(crate) name: String,
+    pub(crate) chain: Vec<ResolvedAddress>,
+    pub(crate) state_path: String,
+    /// The resolve root of the release, i.e. the materialized commit. If the root address is local, it is
+    /// the local directory instead, which cannot be rolled back to.
+    pub(crate) checkout: Utf8PathBuf,
+    pub(crate) run: RunResolved,
+}
+
+/// Directory that holds the releases of an address. Every release gets its own directory named after the
+/// time it was deployed (in unix millis), containing a `release.json` and a `checkout` link to its files. A
+/// `current` link points to the active release.
+pub(crate) fn releases_dir(store_dir: &Utf8Path, address_key: &str) -> Utf8PathBuf {
+    store_dir.join("releases").join(address_key)
+}
+
+const CURRENT: &str = "current";
+const RECORD: &str = "release.json";
+/// Number of releases kept for an address, older ones are removed when a new release is recorded.
+const KEEP_RELEASES: usize = 10;
+
+#[cfg(unix)]
+fn symlink_dir(target: &Utf8Path, link: &Utf8Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink_dir(target: &Utf8Path, link: &Utf8Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_dir(target, link)
+}
+
+/// Points `current` at the release. The new link is created next to the old one and renamed over it, so that
+/// `current` always exists and points to a complete release.
+fn swap_current(releases_dir: &Utf8Path, release_id: &str) -> io::Result<()> {
+    let tmp = releases_dir.join(format!("{}.tmp{}", CURRENT, std::process::id()));
+    if tmp.symlink_metadata().is_ok() {
+        fs::remove_file(&tmp)?;
+    }
+    // The target is relative, so the store dir can be moved
+    symlink_dir(Utf8Path::new(release_id), &tmp)?;
+
+    let current = releases_dir.join(CURRENT);
+    // Windows does not allow renaming over an existing link
+    #[cfg(windows)]
+    if current.symlink_metadata().is_ok() {
+        fs::remove_dir(&current)?;
+    }
+    fs::rename(&tmp, current)
+}
+
+/// Saves the release and makes it the current one.
+pub(crate) fn record_release(
+    releases_dir: &Utf8Path,
+    record: &ReleaseRecord,
+) -> Result<(), Report> {
+    let (release_id, release_dir) =
+        create_release_dir(releases_dir).wrap_err("Failed to create release directory.")?;
+    debug!("Recording release {:?} in {:?}", record, release_dir);
+
+    let contents = serde_json::to_string_pretty(record)?;
+    fs::write(release_dir.join(RECORD), contents).wrap_err("Failed to write release record.")?;
+    symlink_dir(&record.checkout, &release_dir.join("checkout"))
+        .wrap_err("Failed to link release checkout.")?;
+    swap_current(releases_dir, &release_id).wrap_err("Failed to update current release.")?;
+    prune_releases(releases_dir, KEEP_RELEASES).wrap_err("Failed to remove old releases.")?;
+
+    println!("Release {} is now the current release.", release_id);
+    if checkout_root(&record.checkout).is_none() {
+        println!(
+            "Note that it is the local directory {}, rather than a checkout in the store. It cannot be rolled \
+back to, as it might have changed by then.",
+            record.checkout
+        );
+    }
+
+    Ok(())
+}
+
+/// Creates the directory of a new release, named after the current time. If another release was recorded in
+/// the same millisecond, the next free id is used instead.
+fn create_release_dir(releases_dir: &Utf8Path) -> io::Result<(String, Utf8PathBuf)> {
+    fs::create_dir_all(releases_dir)?;
+    let mut millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    loop {
+        let release_id = format!("{:013}", millis);
+        let release_dir = releases_dir.join(&release_id);
+        match fs::create_dir(&release_dir) {
+            Ok(()) => return Ok((release_id, release_dir)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => millis += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Removes all but the newest `keep` releases. The current release is always kept.
+fn prune_releases(releases_dir: &Utf8Path, keep: usize) -> io::Result<()> {
+    let current = current_id(releases_dir)?;
+    let ids = release_ids(releases_dir)?;
+    let excess = ids.len().saturating_sub(keep);
+    for id in ids.iter().take(excess) {
+        if current.as_ref() == Some(id) {
+            continue;
+        }
+        debug!("Removing old release {}", id);
+        // Only removes the link to the checkout, not the checkout itself
+        fs::remove_dir_all(releases_dir.join(id))?;
+    }
+
+    Ok(())
+}
+
+/// Release ids from oldest to newest.
+fn release_ids(releases_dir: &Utf8Path) -> io::Result<Vec<String>> {
+    let mut ids = Vec::new();
+    for entry in releases_dir.read_dir_utf8()? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if entry.file_type()?.is_dir() && name.chars().all(|c| c.is_ascii_digit()) {
+            ids.push(name.to_owned());
+        }
+    }
+    ids.sort();
+
+    Ok(ids)
+}
+
+fn current_id(releases_dir: &Utf8Path) -> io::Result<Option<String>> {
+    match releases_dir.join(CURRENT).read_link_utf8() {
+        Ok(target) => Ok(target.file_name().map(ToOwned::to_owned)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Finds the release that came before the current one.
+pub(crate) fn previous_release(releases_dir: &Utf8Path) -> Result<(String, ReleaseRecord), Report> {
+    let current = current_id(releases_dir)
+        .wrap_err("Failed to read current release.")?
+        .ok_or_else(|| eyre!("There is no current release to roll back from!"))?;
+    let ids = release_ids(releases_dir).wrap_err("Failed to read releases.")?;
+    let previous = ids
+        .into_iter()
+        .rev()
+        .find(|id| id < &current)
+        .ok_or_else(|| {
+            eyre!(
+                "There is no release before the current release {}!",
+                current
+            )
+        })?;
+
+    let contents = fs::read_to_string(releases_dir.join(&previous).join(RECORD))
+        .wrap_err("Failed to read release record.")?;
+    let record = serde_json::from_str(&contents).wrap_err("Invalid release record.")?;
+
+    Ok((previous, record))
+}
+
+/// Makes an existing release the current one again.
+pub(crate) fn activate_release(releases_dir: &Utf8Path, release_id: &str) -> Result<(), Report> {
+    swap_current(releases_dir, release_id).wrap_err("Failed to update current release.")?;
+    println!("Rolled back to release {}.", release_id);
+
+    Ok(())
+}
+
+/// Checkouts of every release that is kept (see [KEEP_RELEASES]) of every address, which should never be
+/// removed from the store so that they can still be rolled back to.
+pub(crate) fn release_checkouts(store_dir: &Utf8Path) -> io::Result<Vec<Utf8PathBuf>> {
+    let all_releases = store_dir.join("releases");
+    if !all_releases.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut checkouts = Vec::new();
+    for entry in all_releases.read_dir_utf8()? {
+        let releases_dir = entry?.into_path();
+        for id in release_ids(&releases_dir)? {
+            let record_path = releases_dir.join(id).join(RECORD);
+            let record = fs::read_to_string(&record_path)
+                .ok()
+                .and_then(|c| serde_json::from_str::<ReleaseRecord>(&c).ok());
+            let Some(record) = record else {
+                debug!("Skipping unreadable release {:?}", record_path);
+                continue;
+            };
+            checkouts.push(record.checkout);
+        }
+    }
+
+    Ok(checkouts)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use camino::Utf8PathBuf;
+
+    use super::{create_release_dir, current_id, prune_releases, release_ids, swap_current};
+
+    #[test]
+    fn prune_keeps_current() {
+        let dir = tempfile::tempdir().unwrap();
+        let releases_dir = Utf8PathBuf::from_path_buf(dir.path().to_owned()).unwrap();
+        for id in [
+            "0000000000001",
+            "0000000000002",
+            "0000000000003",
+            "0000000000004",
+        ] {
+            fs::create_dir_all(releases_dir.join(id)).unwrap();
+        }
+        // Rolled back to the oldest release
+        swap_current(&releases_dir, "0000000000001").unwrap();
+
+        prune_releases(&releases_dir, 2).unwrap();
+        assert_eq!(
+            vec!["0000000000001", "0000000000003", "0000000000004"],
+            release_ids(&releases_dir).unwrap()
+        );
+        assert_eq!(
+            Some("0000000000001".to_owned()),
+            current_id(&releases_dir).unwrap()
+        );
+    }
+
+    #[test]
+    fn release_ids_unique() {
+        let dir = tempfile::tempdir().unwrap();
+        let releases_dir = Utf8PathBuf::from_path_buf(dir.path().to_owned()).unwrap();
+        let ids: Vec<String> = (0..5)
+            .map(|_| create_release_dir(&releases_dir).unwrap().0)
+            .collect();
+
+        assert_eq!(ids, release_ids(&releases_dir).unwrap());
+    }
+}
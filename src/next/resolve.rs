@@ -1,7 +1,8 @@
-use std::{env, fmt::Debug, ops::ControlFlow};
+use std::{env, fmt::Debug, ops::ControlFlow, time::Duration};
 
 use camino::{Utf8Path, Utf8PathBuf};
 use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
 use tracing::{debug, instrument};
 
 use crate::{
@@ -14,8 +15,10 @@ use super::{
         load_dploy_config, merge_vars, traverse_arg_configs, ArgumentConfig, Config, ConfigScope,
         ConfigVar,
     },
-    errors::{ConfigError, ResolutionError, StateError, WrapStateErr},
-    state::ResolveState,
+    errors::{ConfigError, ResolutionError, StateError, StateErrorKind, WrapStateErr},
+    logs::{log_dir, LogOptions, LogRotation},
+    process::RetryPolicy,
+    state::{ResolveState, ROOT_SCOPE_HASH, ROOT_SCOPE_SUB},
 };
 
 #[derive(Default)]
@@ -103,12 +106,47 @@ impl<T, U: Resolvable<T>> Resolvable<Option<T>> for Option<U> {
     }
 }
 
+/// Arguments that determine how the entrypoint process is run, as opposed to what is run.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct ProcessArguments {
+    pub(crate) timeout: Option<u64>,
+    pub(crate) retries: Option<u32>,
+    pub(crate) retry_delay: Option<u64>,
+    pub(crate) retry_on: Option<Vec<i32>>,
+    pub(crate) clean_env: Option<bool>,
+    pub(crate) inherit: Option<Vec<String>>,
+    pub(crate) redact: Option<bool>,
+    pub(crate) pty: Option<bool>,
+    pub(crate) log: Option<bool>,
+    pub(crate) log_max_files: Option<usize>,
+    pub(crate) log_max_size: Option<u64>,
+}
+
+impl Mergeable for ProcessArguments {
+    fn merge(self, other: Self) -> Self {
+        Self {
+            timeout: other.timeout.or(self.timeout),
+            retries: other.retries.or(self.retries),
+            retry_delay: other.retry_delay.or(self.retry_delay),
+            retry_on: other.retry_on.or(self.retry_on),
+            clean_env: other.clean_env.or(self.clean_env),
+            inherit: other.inherit.or(self.inherit),
+            redact: other.redact.or(self.redact),
+            pty: other.pty.or(self.pty),
+            log: other.log.or(self.log),
+            log_max_files: other.log_max_files.or(self.log_max_files),
+            log_max_size: other.log_max_size.or(self.log_max_size),
+        }
+    }
+}
+
 #[derive(Default)]
 pub(crate) struct RunArguments {
     pub(crate) executable: Option<Utf8PathBuf>,
     pub(crate) execution_path: Option<Utf8PathBuf>,
     pub(crate) envs: Vec<ConfigVar>,
     pub(crate) scope_args: SecretScopeArguments,
+    pub(crate) process_args: ProcessArguments,
 }
 
 impl Mergeable for RunArguments {
@@ -119,6 +157,7 @@ impl Mergeable for RunArguments {
             execution_path: other.execution_path.or(self.execution_path),
             envs: merge_vars(self.envs, other.envs),
             scope_args: self.scope_args.merge(other.scope_args),
+            process_args: self.process_args.merge(other.process_args),
         }
     }
 }
@@ -130,6 +169,19 @@ impl RunArguments {
             execution_path: value.execution_path.resolve(resolve_root),
             envs: value.envs.unwrap_or_default(),
             scope_args: value.scope.map(|s| s.into()).unwrap_or_default(),
+            process_args: ProcessArguments {
+                timeout: value.timeout,
+                retries: value.retries,
+                retry_delay: value.retry_delay,
+                retry_on: value.retry_on,
+                clean_env: value.clean_env,
+                inherit: value.inherit,
+                redact: value.redact,
+                pty: value.pty,
+                log: value.log,
+                log_max_files: value.log_max_files,
+                log_max_size: value.log_max_size,
+            },
         }
     }
 }
@@ -139,7 +191,7 @@ pub(crate) struct SecretArguments {
     pub(crate) scope_args: SecretScopeArguments,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct SecretScope {
     pub(crate) service: String,
     pub(crate) name: String,
@@ -147,12 +199,20 @@ pub(crate) struct SecretScope {
     pub(crate) hash: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct RunResolved {
     pub(crate) executable: Utf8PathBuf,
     pub(crate) execution_path: Utf8PathBuf,
     pub(crate) envs: Vec<ConfigVar>,
     pub(crate) scope: SecretScope,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) retry: RetryPolicy,
+    /// If set, only these variables are taken from the environment of the tidploy process.
+    pub(crate) inherit: Option<Vec<String>>,
+    pub(crate) redact: bool,
+    pub(crate) pty: bool,
+    /// Where the output of the entrypoint is logged, if it is.
+    pub(crate) log: Option<LogOptions>,
 }
 
 #[derive(Debug)]
@@ -289,6 +349,36 @@ pub(crate) fn resolve_run(
         .execution_path
         .unwrap_or_else(|| resolve_state.resolve_root.clone());
 
+    let process_args = final_args.process_args;
+    if process_args.clean_env.is_none() && process_args.inherit.is_some() {
+        return Err(StateErrorKind::InvalidRunArgs(
+            "Variables to inherit were given, but clean_env is not set, so every variable is inherited. Set \
+clean_env (or pass --clean-env) to only inherit these."
+                .to_owned(),
+        ))
+        .to_state_err("Failed to resolve run arguments.");
+    }
+    let retry = RetryPolicy {
+        retries: process_args.retries.unwrap_or(0),
+        delay: Duration::from_secs(process_args.retry_delay.unwrap_or(0)),
+        on_codes: process_args.retry_on.unwrap_or_default(),
+    };
+
+    let log = process_args.log.unwrap_or(false).then(|| {
+        let default_rotation = LogRotation::default();
+        LogOptions {
+            dir: log_dir(&resolve_state.store_dir, &resolve_state.address_key),
+            rotation: LogRotation {
+                max_files: process_args
+                    .log_max_files
+                    .unwrap_or(default_rotation.max_files),
+                max_size: process_args
+                    .log_max_size
+                    .unwrap_or(default_rotation.max_size),
+            },
+        }
+    });
+
     let resolved = RunResolved {
         executable: final_args
             .executable
@@ -296,6 +386,15 @@ pub(crate) fn resolve_run(
         execution_path,
         envs: final_args.envs,
         scope,
+        timeout: process_args.timeout.map(Duration::from_secs),
+        retry,
+        inherit: process_args
+            .clean_env
+            .unwrap_or(false)
+            .then(|| process_args.inherit.unwrap_or_default()),
+        redact: process_args.redact.unwrap_or(false),
+        pty: process_args.pty.unwrap_or(false),
+        log,
     };
 
     Ok(resolved)
@@ -371,6 +470,30 @@ impl Resolve<SecretResolved> for SecretArguments {
     }
 }
 
+/// The secret scope of the config at `state_path` (relative to `resolve_root`) for a state named `name`. This
+/// is the scope used for the credentials of Git addresses in that config.
+pub(crate) fn state_secret_scope(
+    resolve_root: &Utf8Path,
+    state_path: &RelativePath,
+    name: &str,
+) -> Result<SecretScope, ResolutionError> {
+    let secret_args = SecretArguments {
+        key: String::new(),
+        scope_args: SecretScopeArguments::default(),
+    };
+    let merged_args = secret_args.merge_env_config(resolve_root, state_path)?;
+
+    let resolved: SecretResolved = Resolve::resolve(
+        merged_args,
+        resolve_root,
+        name,
+        ROOT_SCOPE_SUB,
+        ROOT_SCOPE_HASH,
+    );
+
+    Ok(resolved.scope)
+}
+
 /// Loads config, environment variables and resolves the final arguments to make them ready for final use
 #[instrument(name = "merge_resolve", level = "debug", skip_all)]
 pub(crate) fn merge_and_resolve<T: Debug>(
@@ -1,4 +1,12 @@
-use color_eyre::eyre::{Context, Report};
+use std::{
+    collections::VecDeque,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use camino::Utf8Path;
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Context, Report};
 use relative_path::RelativePathBuf;
 use tracing::{debug, instrument};
 
@@ -6,15 +14,19 @@ use crate::{
     archives::extract_archive,
     filesystem::{get_dirs, WrapToPath},
     next::{
-        resolve::{resolve_run, Resolved, RunArguments, SecretScopeArguments},
-        secrets::secret_vars_to_envs,
-        state::{create_resolve_state, parse_cli_vars, InferContext},
+        resolve::{resolve_run, ProcessArguments, Resolved, RunArguments, SecretScopeArguments},
+        secrets::{secret_vars_to_envs, SecretEnvs},
+        state::{address_key, create_resolve_state, parse_cli_vars, Address, InferContext},
     },
     state::{create_state_create, create_state_run, CliEnvState},
 };
 
 use super::{
-    process::{run_entrypoint, EntrypointOut},
+    cache::lock_checkout,
+    history::{elapsed_ms, record_run, HistoryEntry},
+    lock::{deploy_lock_path, lock_deploy, LockWait},
+    process::{run_entrypoint, EntrypointOptions, EntrypointOut},
+    release::{activate_release, previous_release, record_release, releases_dir, ReleaseRecord},
     resolve::RunResolved,
     state::{AddressIn, StateOptions},
 };
@@ -22,7 +34,8 @@ use super::{
 pub(crate) fn run_command(
     address_in: AddressIn,
     git_infer: bool,
-    service: Option<String>,
+    state_options: StateOptions,
+    run_options: RunOptions,
     executable: Option<String>,
     execution_path: Option<String>,
     variables: Vec<String>,
@@ -30,11 +43,8 @@ pub(crate) fn run_command(
     run_command_input(
         address_in,
         git_infer,
-        None,
-        RunOptions {
-            service,
-            input_bytes: None,
-        },
+        Some(state_options),
+        run_options,
         executable,
         execution_path,
         variables,
@@ -87,12 +97,67 @@ pub(crate) fn run_command_input_old_state(
 
     let relative_path = RelativePathBuf::from(&state.exe_name);
     let exe_path = relative_path.to_utf8_path(state.deploy_dir());
-    run_entrypoint(&state.deploy_dir(), &exe_path, state.envs, input_bytes)
+    let options = EntrypointOptions {
+        input_bytes,
+        ..Default::default()
+    };
+    run_entrypoint(&state.deploy_dir(), &exe_path, state.envs, options)
 }
 
+#[derive(Default, Clone)]
 pub(crate) struct RunOptions {
     pub(crate) service: Option<String>,
     pub(crate) input_bytes: Option<Vec<u8>>,
+    pub(crate) process_args: ProcessArguments,
+    /// If set, the entrypoint is kept running instead of being run once.
+    pub(crate) supervise: Option<SuperviseOptions>,
+    /// Record the checkout as the current release of the address if the entrypoint succeeds.
+    pub(crate) release: bool,
+    /// If set, the deploy lock of the address is held while running, so that the same target is not deployed
+    /// twice at once.
+    pub(crate) lock: Option<LockWait>,
+}
+
+/// When a supervised entrypoint is started again after it exits.
+#[non_exhaustive]
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum RestartPolicy {
+    /// Only restart if the entrypoint failed, stop supervising once it succeeds.
+    OnFailure,
+    /// Always restart the entrypoint, regardless of how it exited.
+    Always,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SuperviseOptions {
+    pub(crate) policy: RestartPolicy,
+    /// Time to wait before the first restart. It doubles after every restart, up to `backoff_max`.
+    pub(crate) backoff: Duration,
+    pub(crate) backoff_max: Duration,
+    /// Supervising is given up if the entrypoint fails more than this many times within `crash_window`.
+    pub(crate) crash_limit: u32,
+    pub(crate) crash_window: Duration,
+}
+
+impl SuperviseOptions {
+    pub(crate) fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            backoff: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(60),
+            crash_limit: 5,
+            crash_window: Duration::from_secs(60),
+        }
+    }
+
+    fn first_backoff(&self) -> Duration {
+        self.backoff.min(self.backoff_max)
+    }
+
+    /// Doubles the backoff, which cannot overflow even if it was set to something huge.
+    fn next_backoff(&self, backoff: Duration) -> Duration {
+        backoff.saturating_mul(2).min(self.backoff_max)
+    }
 }
 
 #[instrument(name = "run", level = "debug", skip_all)]
@@ -107,6 +172,19 @@ pub(crate) fn run_command_input(
 ) -> Result<EntrypointOut, Report> {
     debug!("Run command called with addr_in {:?}, executable {:?}, variables {:?} and input_bytes {:?}", addr_in, executable, variables, run_options.input_bytes);
 
+    // An entrypoint that is always restarted never finishes, so it never becomes a release
+    if run_options.release
+        && run_options
+            .supervise
+            .as_ref()
+            .is_some_and(|s| s.policy == RestartPolicy::Always)
+    {
+        return Err(eyre!(
+            "Cannot record a release when supervising with restart policy always, as the entrypoint never \
+finishes!"
+        ));
+    }
+
     let scope_args = SecretScopeArguments {
         service: run_options.service,
         ..Default::default()
@@ -116,30 +194,290 @@ pub(crate) fn run_command_input(
     } else {
         InferContext::Cwd
     };
-    let resolve_state =
-        create_resolve_state(addr_in, infer_ctx, state_options.unwrap_or_default())?;
+    let state_options = state_options.unwrap_or_default();
+    let address = Address::from_addr_in(addr_in.clone(), infer_ctx)?;
+    // Taken before resolving, so that two deploys of the same target also do not fetch, check out or write
+    // the lock file at the same time
+    let _deploy_lock = run_options
+        .lock
+        .map(|wait| {
+            lock_deploy(
+                &deploy_lock_path(&state_options.store_dir, &address.key()),
+                wait,
+            )
+        })
+        .transpose()?;
+
+    // Runs that fail before the entrypoint is started are recorded as well, with what is known before resolving
+    let store_dir = state_options.store_dir.clone();
+    let started = SystemTime::now();
+    let record_failure = |e: Report| {
+        let mut entry = HistoryEntry::new(
+            started,
+            address.name.clone(),
+            Vec::new(),
+            address.state_path.to_string(),
+            executable.clone().unwrap_or_default(),
+        );
+        entry.error = Some(e.to_string());
+        record_run(&store_dir, &entry);
+        e
+    };
+
+    let mut resolve_state = create_resolve_state(addr_in, infer_ctx, state_options)
+        .map_err(|e| record_failure(e.into()))?;
+    // Keeps the checkouts from being pruned until the entrypoint has exited
+    let _checkout_locks = std::mem::take(&mut resolve_state.checkout_locks);
     let run_args = RunArguments {
-        executable: executable.resolve(&resolve_state.resolve_root),
+        executable: executable.clone().resolve(&resolve_state.resolve_root),
         execution_path: execution_path.resolve(&resolve_state.resolve_root),
         envs: parse_cli_vars(variables),
         scope_args,
+        process_args: run_options.process_args,
+    };
+
+    let releases_dir = releases_dir(&store_dir, &resolve_state.address_key);
+    let name = resolve_state.name.clone();
+    let chain = resolve_state.chain.clone();
+    let state_path = resolve_state.state_path.to_string();
+    let checkout = resolve_state.resolve_root.clone();
+
+    let run_resolved =
+        resolve_run(resolve_state, run_args).map_err(|e| record_failure(e.into()))?;
+    let entry = |started| {
+        HistoryEntry::new(
+            started,
+            name.clone(),
+            chain.clone(),
+            state_path.clone(),
+            run_resolved.executable.to_string(),
+        )
+    };
+
+    // When supervising, every time the entrypoint is started is recorded separately
+    let out = match run_options.supervise {
+        Some(supervise) => supervise_unit(supervise, || {
+            run_recorded(&store_dir, entry(SystemTime::now()), || {
+                run_unit_input(run_resolved.clone(), run_options.input_bytes.clone())
+            })
+        }),
+        None => run_recorded(&store_dir, entry(started), || {
+            run_unit_input(run_resolved.clone(), run_options.input_bytes)
+        }),
+    }?;
+
+    if run_options.release {
+        if out.exit.success() {
+            let release = ReleaseRecord {
+                name,
+                chain,
+                state_path,
+                checkout,
+                run: run_resolved,
+            };
+            record_release(&releases_dir, &release)?;
+        } else {
+            println!("Entrypoint failed, so the current release was not changed.");
+        }
+    }
+
+    Ok(out)
+}
+
+/// Runs the entrypoint and records the result in the history ledger, whether it succeeded or not.
+fn run_recorded(
+    store_dir: &Utf8Path,
+    mut entry: HistoryEntry,
+    run: impl FnOnce() -> Result<EntrypointOut, Report>,
+) -> Result<EntrypointOut, Report> {
+    let started = Instant::now();
+    let out = run();
+
+    entry.duration_ms = elapsed_ms(started.elapsed());
+    match &out {
+        Ok(out) => entry.exit_code = out.exit.code(),
+        Err(e) => entry.error = Some(e.to_string()),
+    }
+    record_run(store_dir, &entry);
+
+    out
+}
+
+/// Runs the release before the current one again, with the arguments it was originally resolved with. If
+/// it succeeds, it becomes the current release.
+#[instrument(name = "rollback", level = "debug", skip_all)]
+pub(crate) fn rollback_command(
+    addr_in: AddressIn,
+    git_infer: bool,
+    state_options: Option<StateOptions>,
+) -> Result<EntrypointOut, Report> {
+    let infer_ctx = if git_infer {
+        InferContext::Git
+    } else {
+        InferContext::Cwd
     };
+    let key = address_key(addr_in, infer_ctx)?;
+    let store_dir = state_options.unwrap_or_default().store_dir;
+    let releases_dir = releases_dir(&store_dir, &key);
+    let _deploy_lock = lock_deploy(&deploy_lock_path(&store_dir, &key), LockWait::Wait)?;
+
+    let (release_id, record) = previous_release(&releases_dir)?;
+    debug!("Rolling back to release {} {:?}", release_id, record);
+    let checkout_lock = lock_checkout(&record.checkout)?;
+    if !record.checkout.exists() {
+        return Err(eyre!(
+            "The checkout of release {} at {:?} no longer exists!",
+            release_id,
+            record.checkout
+        ));
+    }
+    if checkout_lock.is_none() {
+        return Err(eyre!(
+            "Release {} was run from the local directory {:?} rather than a checkout in the store. It might \
+have changed since, so it cannot be rolled back to!",
+            release_id,
+            record.checkout
+        ));
+    }
+
+    let entry = HistoryEntry::new(
+        SystemTime::now(),
+        record.name,
+        record.chain,
+        record.state_path,
+        record.run.executable.to_string(),
+    );
+    let out = run_recorded(&store_dir, entry, || run_unit_input(record.run, None))?;
 
-    let run_resolved = resolve_run(resolve_state, run_args)?;
+    if out.exit.success() {
+        activate_release(&releases_dir, &release_id)?;
+    } else {
+        println!(
+            "Release {} failed, so the current release was not changed.",
+            release_id
+        );
+    }
 
-    run_unit_input(run_resolved, run_options.input_bytes)
+    Ok(out)
+}
+
+/// Keeps the entrypoint running according to the restart policy, with exponential backoff between restarts.
+/// Every start calls `run`, which loads the secrets again, so rotated values take effect. Returns the output of
+/// the last run once the policy says to stop, or an error if the entrypoint is stuck in a crash loop.
+pub(crate) fn supervise_unit(
+    options: SuperviseOptions,
+    mut run: impl FnMut() -> Result<EntrypointOut, Report>,
+) -> Result<EntrypointOut, Report> {
+    let mut backoff = options.first_backoff();
+    let mut crashes: VecDeque<Instant> = VecDeque::new();
+    let mut runs: u32 = 0;
+
+    loop {
+        let started = Instant::now();
+        let out = run()?;
+        runs += 1;
+        let failed = !out.exit.success();
+        debug!(
+            "Supervised entrypoint exited with {:?} after run {}.",
+            out.exit, runs
+        );
+
+        if !failed && options.policy == RestartPolicy::OnFailure {
+            return Ok(out);
+        }
+
+        if failed {
+            let now = Instant::now();
+            crashes.push_back(now);
+            while crashes
+                .front()
+                .is_some_and(|c| now.duration_since(*c) > options.crash_window)
+            {
+                crashes.pop_front();
+            }
+            if crashes.len() > options.crash_limit as usize {
+                return Err(eyre!(
+                    "Entrypoint failed {} times within {:?}, giving up! Last exit status: {}",
+                    crashes.len(),
+                    options.crash_window,
+                    out.exit
+                ));
+            }
+        }
+
+        // If it ran for a while it was healthy, so we start backing off from the beginning again
+        if started.elapsed() > options.backoff_max {
+            backoff = options.first_backoff();
+        }
+        println!(
+            "Entrypoint exited ({}), restarting in {:?}...",
+            out.exit, backoff
+        );
+        thread::sleep(backoff);
+        backoff = options.next_backoff(backoff);
+    }
 }
 
 pub(crate) fn run_unit_input(
     run_resolved: RunResolved,
     input_bytes: Option<Vec<u8>>,
 ) -> Result<EntrypointOut, Report> {
-    let secret_vars = secret_vars_to_envs(&run_resolved.scope, run_resolved.envs)?;
+    // The secret files are removed once `files` is dropped, which happens after the entrypoint exits
+    let SecretEnvs {
+        envs,
+        values,
+        files,
+    } = secret_vars_to_envs(&run_resolved.scope, run_resolved.envs)?;
+    if let Some(files) = &files {
+        debug!("Secrets delivered as files in {:?}", files.path());
+    }
+
+    let options = EntrypointOptions {
+        input_bytes,
+        timeout: run_resolved.timeout,
+        retry: run_resolved.retry,
+        inherit: run_resolved.inherit,
+        redact: if run_resolved.redact {
+            values
+        } else {
+            Vec::new()
+        },
+        pty: run_resolved.pty,
+        log: run_resolved.log,
+    };
 
     run_entrypoint(
         &run_resolved.execution_path,
         &run_resolved.executable,
-        secret_vars,
-        input_bytes,
+        envs,
+        options,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{RestartPolicy, SuperviseOptions};
+
+    #[test]
+    fn backoff_clamped() {
+        let mut options = SuperviseOptions::new(RestartPolicy::Always);
+        assert_eq!(Duration::from_secs(1), options.first_backoff());
+        assert_eq!(
+            Duration::from_secs(2),
+            options.next_backoff(options.first_backoff())
+        );
+
+        options.backoff = Duration::MAX;
+        options.backoff_max = Duration::MAX;
+        assert_eq!(Duration::MAX, options.next_backoff(options.first_backoff()));
+
+        options.backoff_max = Duration::from_secs(60);
+        assert_eq!(Duration::from_secs(60), options.first_backoff());
+        assert_eq!(
+            Duration::from_secs(60),
+            options.next_backoff(Duration::from_secs(45))
+        );
+    }
+}
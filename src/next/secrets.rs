@@ -1,18 +1,30 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+#[cfg(unix)]
+use std::{
+    fs::Permissions,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+};
 
 use color_eyre::eyre::Report;
 use keyring::{Entry, Error as KeyringError};
 use rpassword::prompt_password;
+use tempfile::TempDir;
 use tracing::{debug, instrument};
 
 use crate::next::{
     resolve::{merge_and_resolve, SecretArguments, SecretScopeArguments},
-    state::{create_resolve_state, InferContext},
+    state::{create_resolve_state, create_root_state, InferContext},
 };
 
 use super::{
-    config::ConfigVar,
-    errors::{SecretError, SecretKeyringError, StateError, WrapStateErr},
+    config::{ConfigVar, VarDelivery},
+    errors::{SecretError, SecretKeyringError, StateError, StateErrorKind, WrapStateErr},
+    fs::secret_files_base,
     resolve::SecretScope,
     state::{AddressIn, StateOptions},
 };
@@ -109,6 +121,7 @@ pub(crate) fn secret_command(
     service: Option<String>,
     key: String,
     prompt: Option<String>,
+    follow: bool,
 ) -> Result<String, Report> {
     debug!(
         "Secret command called with in_addr {:?}, key {:?} and prompt {:?}",
@@ -125,8 +138,12 @@ pub(crate) fn secret_command(
     } else {
         InferContext::Git
     };
-    let resolve_state =
-        create_resolve_state(addr_in, infer_ctx, state_options.unwrap_or_default())?;
+    let state_options = state_options.unwrap_or_default();
+    let resolve_state = if follow {
+        create_resolve_state(addr_in, infer_ctx, state_options)?
+    } else {
+        create_root_state(addr_in, infer_ctx, state_options)?
+    };
 
     let secret_resolved = merge_and_resolve(secret_args, resolve_state)?;
 
@@ -136,17 +153,152 @@ pub(crate) fn secret_command(
     Ok(store_key)
 }
 
+/// A private directory that secrets delivered as files are written to. The directory and everything in it
+/// is removed when this is dropped, so it must be kept alive until the entrypoint has exited.
+pub(crate) struct SecretFiles {
+    dir: TempDir,
+}
+
+impl SecretFiles {
+    pub(crate) fn new() -> io::Result<Self> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("tidploy_secrets_");
+        #[cfg(unix)]
+        builder.permissions(Permissions::from_mode(0o700));
+        let dir = builder.tempdir_in(secret_files_base())?;
+
+        Ok(Self { dir })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Writes the secret to a file that is only accessible to the current user and returns its path.
+    pub(crate) fn write(&self, name: &str, secret: &str) -> io::Result<PathBuf> {
+        let path = self.dir.path().join(name);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&path)?;
+        file.write_all(secret.as_bytes())?;
+
+        Ok(path)
+    }
+}
+
+/// Whether `name` can be used as the name of an environment variable, i.e. it consists of letters, digits and
+/// underscores and does not start with a digit. As it is also used as the name of a secret file, this keeps
+/// it from pointing outside the secrets directory.
+fn is_valid_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The environment variables that deliver the secrets to the entrypoint.
+pub(crate) struct SecretEnvs {
+    pub(crate) envs: HashMap<String, String>,
+    /// The secret values themselves, including those delivered as files.
+    pub(crate) values: Vec<String>,
+    /// Only exists if at least one secret is delivered as a file.
+    pub(crate) files: Option<SecretFiles>,
+}
+
 pub(crate) fn secret_vars_to_envs(
     scope: &SecretScope,
     vars: Vec<ConfigVar>,
-) -> Result<HashMap<String, String>, StateError> {
+) -> Result<SecretEnvs, StateError> {
     let mut envs = HashMap::<String, String>::new();
+    let mut files: Option<SecretFiles> = None;
+    let mut values = Vec::new();
     for e in vars {
+        let delivery = e.delivery.unwrap_or_default();
+        // The name of a secret file must not point outside the secrets directory
+        if delivery == VarDelivery::File && !is_valid_env_name(&e.env_name) {
+            return Err(StateErrorKind::InvalidRunArgs(format!(
+                "{:?} (for secret {}) is not a valid environment variable name, so it cannot be delivered as a \
+file.",
+                e.env_name, e.key
+            )))
+            .to_state_err("Invalid secret variable.");
+        }
         debug!("Getting pass for {:?}", e);
         let pass = get_secret(scope, &e.key)
             .to_state_err("Getting secret for config var to create env map.".to_owned())?;
+        values.push(pass.clone());
+
+        match delivery {
+            VarDelivery::Env => {
+                envs.insert(e.env_name, pass);
+            }
+            VarDelivery::File => {
+                let secret_files = match files {
+                    Some(ref secret_files) => secret_files,
+                    None => files.insert(
+                        SecretFiles::new()
+                            .to_state_err("Failed to create directory for secret files.")?,
+                    ),
+                };
+                let path = secret_files
+                    .write(&e.env_name, &pass)
+                    .to_state_err("Failed to write secret to file.")?;
+                let path = path
+                    .into_os_string()
+                    .into_string()
+                    .map_err(|_e| StateError {
+                        msg: "Secret file path is not valid UTF-8!".to_owned(),
+                        source: Box::new(StateErrorKind::InvalidPath.into()),
+                    })?;
+
+                envs.insert(format!("{}_FILE", e.env_name), path);
+            }
+        }
+    }
+    Ok(SecretEnvs {
+        envs,
+        values,
+        files,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
 
-        envs.insert(e.env_name, pass);
+    use super::{is_valid_env_name, SecretFiles};
+
+    #[test]
+    fn secret_files_private() {
+        let files = SecretFiles::new().unwrap();
+        let dir = files.path().to_owned();
+        let path = files.write("DB_PASSWORD", "hunter2").unwrap();
+
+        assert_eq!("hunter2", fs::read_to_string(&path).unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let file_mode = fs::metadata(&path).unwrap().permissions().mode();
+            let dir_mode = fs::metadata(&dir).unwrap().permissions().mode();
+            assert_eq!(0o600, file_mode & 0o777);
+            assert_eq!(0o700, dir_mode & 0o777);
+        }
+
+        drop(files);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn env_names() {
+        for name in ["DB_PASSWORD", "_private", "a1"] {
+            assert!(is_valid_env_name(name), "{}", name);
+        }
+        for name in ["", "1A", "A-B", "../A", "A/B", "A=B", "A B"] {
+            assert!(!is_valid_env_name(name), "{}", name);
+        }
     }
-    Ok(envs)
 }
@@ -1,7 +1,8 @@
-use std::env::current_dir;
+use std::env::{self, current_dir};
 
 use camino::{Utf8Path, Utf8PathBuf};
 use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
 use tracing::debug;
 
 use crate::{
@@ -10,23 +11,31 @@ use crate::{
 };
 
 use super::{
-    config::{ConfigAddress, ConfigVar},
-    errors::{AddressError, StateError, StateErrorKind, WrapStateErr},
+    config::{ConfigAddress, ConfigVar, VerifyMode},
+    credentials::CredentialKeys,
+    errors::{AddressError, ResolutionError, StateError, StateErrorKind, WrapStateErr},
     fs::get_dirs,
-    git::get_dir_from_git,
+    git::{get_dir_from_git, hash_last_n},
+    giturl::GitUrl,
+    lock::FileLock,
+    lockfile::{
+        read_lockfile, root_lock_dir, sync_git_root_lockfile, sync_lockfile, GitPin, LockMode,
+        Lockfile,
+    },
+    resolve::{state_secret_scope, SecretScope},
 };
 
-#[derive(Debug)]
+/// The sub and hash of the secret scope of a resolve state.
+pub(crate) const ROOT_SCOPE_SUB: &str = "tidploy_root";
+pub(crate) const ROOT_SCOPE_HASH: &str = "todo_hash";
+
+#[derive(Debug, Default, Clone, Copy)]
 pub(crate) enum InferContext {
     Cwd,
+    #[default]
     Git,
 }
 
-impl Default for InferContext {
-    fn default() -> Self {
-        Self::Git
-    }
-}
 #[derive(Debug, Clone, Default)]
 pub struct LocalAddressIn {
     pub resolve_root: Option<String>,
@@ -39,6 +48,12 @@ pub struct GitAddressIn {
     pub git_ref: Option<String>,
     pub target_resolve_root: Option<String>,
     pub state_path: Option<String>,
+    /// Paths outside of the state path to also check out, relative to the target resolve root.
+    pub include_paths: Vec<String>,
+    /// Also check out the submodules inside the checked out paths.
+    pub submodules: bool,
+    /// Also download the Git LFS files that are checked out. Requires git-lfs to be installed.
+    pub lfs: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -75,8 +90,25 @@ impl AddressIn {
             git_ref,
             target_resolve_root,
             state_path,
+            ..Default::default()
         })
     }
+
+    /// Sets what is checked out besides the state path, if this is a Git address.
+    pub(crate) fn with_checkout(
+        mut self,
+        include_paths: Vec<String>,
+        submodules: bool,
+        lfs: bool,
+    ) -> Self {
+        if let AddressIn::Git(address) = &mut self {
+            address.include_paths = include_paths;
+            address.submodules = submodules;
+            address.lfs = lfs;
+        }
+
+        self
+    }
 }
 
 /// Parses the list of strings given and interprets them as each pair of two being a secret key and target
@@ -88,6 +120,7 @@ pub(crate) fn parse_cli_vars(envs: Vec<String>) -> Vec<ConfigVar> {
         .map(|c| ConfigVar {
             key: c.first().unwrap().to_owned(),
             env_name: c.get(1).unwrap().to_owned(),
+            delivery: None,
         })
         .collect()
 }
@@ -114,7 +147,7 @@ fn get_current_dir() -> Result<Utf8PathBuf, StateError> {
     )
     .map_err(|_e| StateError {
         msg: "Current directory is not UTF-8!".to_owned(),
-        source: StateErrorKind::InvalidPath.into(),
+        source: Box::new(StateErrorKind::InvalidPath.into()),
     })
 }
 
@@ -135,7 +168,28 @@ fn url_local(url: String, local: bool, relative: &Utf8Path) -> String {
 }
 
 impl Address {
-    fn from_config_addr(value: ConfigAddress, resolve_root: &Utf8Path) -> Result<Self, StateError> {
+    /// Identifies the address independent of the version it resolves to, in the form `{name}_{hash}`. Used to
+    /// key state that is kept per address, such as run logs.
+    pub(crate) fn key(&self) -> String {
+        let id = match &self.root {
+            AddressRoot::Local(path) => format!("local:{}:{}", path, self.state_path.normalize()),
+            AddressRoot::Git(addr) => format!(
+                "git:{}:{}:{}",
+                GitUrl::parse(&addr.url).map_or_else(|_| addr.url.clone(), |u| u.identity()),
+                addr.path.normalize(),
+                self.state_path.normalize()
+            ),
+        };
+
+        format!("{}_{}", self.name, hash_last_n(&id, 8))
+    }
+
+    /// `scope` gives the secret scope of the config, which is only determined if the address needs it.
+    fn from_config_addr(
+        value: ConfigAddress,
+        resolve_root: &Utf8Path,
+        scope: impl FnOnce() -> Result<SecretScope, StateError>,
+    ) -> Result<Self, StateError> {
         debug!("Converting config_adress {:?} to address!", value);
 
         let addr = match value {
@@ -145,11 +199,34 @@ impl Address {
                 git_ref,
                 target_path,
                 state_path,
+                verify,
+                allowed_signers,
+                include_paths,
+                submodules,
+                lfs,
+                token,
+                token_user,
+                ssh_key,
                 // arg_root,
                 // arg_path
             } => {
                 let local = local.unwrap_or_default();
                 let url = url_local(url, local, resolve_root);
+                let verify = verify.map(|mode| Verify {
+                    mode,
+                    allowed_signers: allowed_signers
+                        .map(|p| Utf8PathBuf::from(url_local(p, true, resolve_root))),
+                });
+                let credentials = if token.is_some() || ssh_key.is_some() {
+                    Some(Box::new(CredentialKeys {
+                        token,
+                        token_user,
+                        ssh_key,
+                        scope: scope()?,
+                    }))
+                } else {
+                    None
+                };
                 let name = parse_url_name(&url).to_state_err("Cannot get name from url.")?;
                 Address {
                     name,
@@ -158,6 +235,15 @@ impl Address {
                         local,
                         git_ref,
                         path: RelativePathBuf::from(target_path.unwrap_or_default()),
+                        include_paths: include_paths
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(RelativePathBuf::from)
+                            .collect(),
+                        submodules: submodules.unwrap_or_default(),
+                        lfs: lfs.unwrap_or_default(),
+                        verify,
+                        credentials,
                     }),
                     state_path: RelativePathBuf::from(state_path.unwrap_or_default()),
                     // arg_root: RelativePathBuf::from(arg_root.unwrap_or_default()),
@@ -194,7 +280,10 @@ impl Address {
         Ok(addr)
     }
 
-    fn from_addr_in(value: AddressIn, infer_ctx: InferContext) -> Result<Self, StateError> {
+    pub(crate) fn from_addr_in(
+        value: AddressIn,
+        infer_ctx: InferContext,
+    ) -> Result<Self, StateError> {
         debug!("Converting config_adress {:?} to address!", value);
 
         let addr = match value {
@@ -204,6 +293,9 @@ impl Address {
                 git_ref,
                 state_path,
                 target_resolve_root,
+                include_paths,
+                submodules,
+                lfs,
             }) => {
                 let current_dir = get_current_dir()?;
 
@@ -227,6 +319,14 @@ impl Address {
                         local,
                         git_ref: git_ref.unwrap_or_else(|| "HEAD".to_owned()),
                         path: RelativePathBuf::from(target_resolve_root.unwrap_or_default()),
+                        include_paths: include_paths
+                            .into_iter()
+                            .map(RelativePathBuf::from)
+                            .collect(),
+                        submodules,
+                        lfs,
+                        verify: None,
+                        credentials: None,
                     }),
                     state_path: RelativePathBuf::from(state_path.unwrap_or_default()),
                     // arg_root: RelativePathBuf::from(arg_root.unwrap_or_default()),
@@ -279,6 +379,41 @@ pub(crate) struct GitAddress {
     pub(crate) local: bool,
     pub(crate) git_ref: String,
     pub(crate) path: RelativePathBuf,
+    /// Checked out in addition to the state path, relative to `path`.
+    pub(crate) include_paths: Vec<RelativePathBuf>,
+    pub(crate) submodules: bool,
+    pub(crate) lfs: bool,
+    pub(crate) verify: Option<Verify>,
+    pub(crate) credentials: Option<Box<CredentialKeys>>,
+}
+
+/// Signature verification of a Git address, see [VerifyMode].
+#[derive(Debug, Clone)]
+pub(crate) struct Verify {
+    pub(crate) mode: VerifyMode,
+    pub(crate) allowed_signers: Option<Utf8PathBuf>,
+}
+
+/// An address as it was actually resolved, so it can be recorded what ran. Unlike [Address], a Git address
+/// includes the commit its ref pointed to at the time.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ResolvedAddress {
+    Local {
+        path: String,
+        state_path: String,
+    },
+    Git {
+        url: String,
+        git_ref: String,
+        /// The tag chosen when `git_ref` is a `semver:` or `latest-tag:` selector.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tag: Option<String>,
+        commit: String,
+        path: String,
+        state_path: String,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -288,7 +423,7 @@ pub(crate) enum StateStep {
     Config,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub(crate) struct State {
     pub(crate) name: String,
     // pub(crate) arg_root: RelativePathBuf,
@@ -296,6 +431,10 @@ pub(crate) struct State {
     pub(crate) resolve_root: Utf8PathBuf,
     pub(crate) state_path: RelativePathBuf,
     pub(crate) step: StateStep, // pub(crate) address: Option<Address>,
+    /// Every address that was resolved to get to this state, in order.
+    pub(crate) chain: Vec<ResolvedAddress>,
+    /// Shared locks on the checkouts of the Git addresses in the chain, which keep them from being pruned.
+    pub(crate) checkout_locks: Vec<FileLock>,
 }
 
 impl State {
@@ -337,6 +476,14 @@ pub(crate) struct ResolveState {
     pub(crate) name: String,
     pub(crate) sub: String,
     pub(crate) hash: String,
+    /// Key of the address resolution started from, see [Address::key].
+    pub(crate) address_key: String,
+    pub(crate) store_dir: Utf8PathBuf,
+    pub(crate) chain: Vec<ResolvedAddress>,
+    /// The commits the Git addresses in the chain are pinned to, see [sync_lockfile].
+    pub(crate) pins: Vec<GitPin>,
+    /// See [State::checkout_locks], these should be held until the entrypoint has exited.
+    pub(crate) checkout_locks: Vec<FileLock>,
 }
 
 // #[instrument(name = "converge", level = "debug", skip_all)]
@@ -371,35 +518,16 @@ pub(crate) struct ResolveState {
 //     Ok(state)
 // }
 
-/// Parse a repo URL to extract a "name" from it, as well as encode the part before the name to still uniquely
-/// identify it. Only supports forward slashes as path seperator.
+/// Parse a repo URL to extract a "name" from it, i.e. the last component of its path without a `.git` suffix.
 pub(crate) fn parse_url_name(url: &str) -> Result<String, AddressError> {
-    let url = url.strip_suffix('/').unwrap_or(url).to_owned();
-    // We want the final part, after the slash, as the "file name"
-    let split_parts: Vec<&str> = url.split('/').collect();
-
-    // If last does not exist then the string is empty so invalid
-    let last_part = *split_parts
-        .last()
-        .ok_or(AddressError::RepoParse(url.to_owned()))?;
-
-    // In case there is a file extension (such as `.git`), we don't want that part of the name
-    let split_parts_dot: Vec<&str> = last_part.split('.').collect();
-    let name = if split_parts_dot.len() <= 1 {
-        // In this case no "." exists and we return just the entire "file name"
-        last_part.to_owned()
-    } else {
-        // We get only the part that comes before the first .
-        (*split_parts_dot
-            .first()
-            .ok_or(AddressError::RepoParse(url.clone()))?)
-        .to_owned()
-    };
-
-    Ok(name)
+    Ok(GitUrl::parse(url)?.name().to_owned())
 }
 
-fn resolve_address(address: Address, store_dir: &Utf8Path) -> Result<State, StateError> {
+fn resolve_address(
+    address: Address,
+    opt: &StateOptions,
+    lock: &Lockfile,
+) -> Result<State, StateError> {
     debug!("Resolving address {:?}", address);
 
     let Address {
@@ -409,9 +537,16 @@ fn resolve_address(address: Address, store_dir: &Utf8Path) -> Result<State, Stat
     } = address;
 
     match root {
-        AddressRoot::Git(addr) => get_dir_from_git(addr, state_path, store_dir),
+        AddressRoot::Git(addr) => {
+            get_dir_from_git(addr, state_path, &opt.store_dir, opt.offline, lock)
+        }
         AddressRoot::Local(path) => Ok(State {
             name,
+            chain: vec![ResolvedAddress::Local {
+                path: path.to_string(),
+                state_path: state_path.to_string(),
+            }],
+            checkout_locks: Vec::new(),
             resolve_root: path,
             state_path,
             step: StateStep::Config,
@@ -419,23 +554,45 @@ fn resolve_address(address: Address, store_dir: &Utf8Path) -> Result<State, Stat
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct StateOptions {
     pub(crate) store_dir: Utf8PathBuf,
+    /// Only use the repositories and checkouts already in the store, without contacting any Git remote.
+    pub(crate) offline: bool,
+    pub(crate) lock_mode: LockMode,
+    /// The commit to use for the root Git address, instead of asking the remote what its ref points to. Only
+    /// used if the URL and ref match.
+    pub(crate) root_pin: Option<GitPin>,
 }
 
 impl Default for StateOptions {
     fn default() -> Self {
         Self {
             store_dir: get_dirs().cache.clone(),
+            offline: env::var("TIDPLOY_OFFLINE").is_ok_and(|v| !v.is_empty()),
+            lock_mode: LockMode::default(),
+            root_pin: None,
         }
     }
 }
 
-pub(crate) fn converge_state(mut state: State, opt: StateOptions) -> Result<State, StateError> {
+pub(crate) fn converge_state(
+    mut state: State,
+    opt: &StateOptions,
+    lock: &Lockfile,
+) -> Result<State, StateError> {
     loop {
         match state.step {
             StateStep::None => break,
-            StateStep::Address(address) => state = resolve_address(address, &opt.store_dir)?,
+            StateStep::Address(address) => {
+                let mut chain = state.chain;
+                let mut checkout_locks = state.checkout_locks;
+                state = resolve_address(address, opt, lock)?;
+                chain.append(&mut state.chain);
+                state.chain = chain;
+                checkout_locks.append(&mut state.checkout_locks);
+                state.checkout_locks = checkout_locks;
+            }
             StateStep::Config => {
                 let config_dir = state.state_path.to_utf8_path(&state.resolve_root);
                 let config = load_dploy_config(&config_dir)
@@ -444,8 +601,13 @@ pub(crate) fn converge_state(mut state: State, opt: StateOptions) -> Result<Stat
                 let addr = config.and_then(|c| c.address);
                 match addr {
                     Some(addr) => {
+                        let scope = || {
+                            state_secret_scope(&state.resolve_root, &state.state_path, &state.name)
+                                .map_err(|ResolutionError::Config(e)| e)
+                                .to_state_err("Failed to resolve secret scope of config.")
+                        };
                         state.step =
-                            StateStep::Address(Address::from_config_addr(addr, &config_dir)?)
+                            StateStep::Address(Address::from_config_addr(addr, &config_dir, scope)?)
                     }
                     None => state.step = StateStep::None,
                 }
@@ -456,24 +618,110 @@ pub(crate) fn converge_state(mut state: State, opt: StateOptions) -> Result<Stat
     Ok(state)
 }
 
+/// Determines the key of an address without resolving it, see [Address::key].
+pub(crate) fn address_key(
+    addr_in: AddressIn,
+    infer_ctx: InferContext,
+) -> Result<String, StateError> {
+    Ok(Address::from_addr_in(addr_in, infer_ctx)?.key())
+}
+
+/// The Git address resolution starts from, or None if it is a local address.
+pub(crate) fn root_git_address(
+    addr_in: AddressIn,
+    infer_ctx: InferContext,
+) -> Result<Option<GitAddress>, StateError> {
+    match Address::from_addr_in(addr_in, infer_ctx)?.root {
+        AddressRoot::Git(address) => Ok(Some(address)),
+        AddressRoot::Local(_) => Ok(None),
+    }
+}
+
 pub(crate) fn create_resolve_state(
     addr_in: AddressIn,
     infer_ctx: InferContext,
     opt: StateOptions,
 ) -> Result<ResolveState, StateError> {
     let address = Address::from_addr_in(addr_in, infer_ctx)?;
-    let state = resolve_address(address, &opt.store_dir)?;
-    let state = converge_state(state, opt)?;
+    let address_key = address.key();
+    let store_dir = opt.store_dir.clone();
+    let git_root = matches!(address.root, AddressRoot::Git(_));
+
+    // The commit of a root Git address is pinned in the store, see [root_lock_dir]
+    let root_lock_dir = root_lock_dir(&store_dir, &address_key);
+    let old_root_lock = if git_root {
+        read_lockfile(&root_lock_dir).to_state_err("Failed to load lock file.")?
+    } else {
+        None
+    };
+    let root_lock = match (&opt.root_pin, opt.lock_mode) {
+        (Some(pin), _) => Lockfile::from_pins(vec![pin.clone()]),
+        (None, LockMode::Update) => Lockfile::default(),
+        (None, _) => old_root_lock.clone().unwrap_or_default(),
+    };
+    let state = resolve_address(address, &opt, &root_lock)?;
+
+    // The lock file is next to the root config, which is the first one loaded when converging
+    let lock_dir = state.state_path.to_utf8_path(&state.resolve_root);
+    let old_lock = read_lockfile(&lock_dir).to_state_err("Failed to load lock file.")?;
+    let lock = match opt.lock_mode {
+        // A lock file that is part of a repository cannot be updated, so it is still used
+        LockMode::Update if !git_root => Lockfile::default(),
+        _ => old_lock.clone().unwrap_or_default(),
+    };
+    let state = converge_state(state, &opt, &lock)?;
+    let pins = if git_root {
+        sync_git_root_lockfile(
+            &root_lock_dir,
+            old_root_lock,
+            &lock_dir,
+            old_lock,
+            &state.chain,
+            opt.lock_mode,
+        )?
+    } else {
+        sync_lockfile(&lock_dir, old_lock, &state.chain, opt.lock_mode)?
+    };
+
+    Ok(to_resolve_state(state, address_key, store_dir, pins))
+}
+
+/// Like [create_resolve_state], but does not follow the address in the root config, nor use its lock file.
+/// The secret scope is then that of the root config, which is also the scope of the credentials of the Git
+/// address in it.
+pub(crate) fn create_root_state(
+    addr_in: AddressIn,
+    infer_ctx: InferContext,
+    opt: StateOptions,
+) -> Result<ResolveState, StateError> {
+    let address = Address::from_addr_in(addr_in, infer_ctx)?;
+    let address_key = address.key();
+    let store_dir = opt.store_dir.clone();
+    let state = resolve_address(address, &opt, &Lockfile::default())?;
+
+    Ok(to_resolve_state(state, address_key, store_dir, Vec::new()))
+}
 
+fn to_resolve_state(
+    state: State,
+    address_key: String,
+    store_dir: Utf8PathBuf,
+    pins: Vec<GitPin>,
+) -> ResolveState {
     let resolve_state = ResolveState {
         resolve_root: state.resolve_root,
         state_path: state.state_path,
         name: state.name,
-        sub: "tidploy_root".to_owned(),
-        hash: "todo_hash".to_owned(),
+        sub: ROOT_SCOPE_SUB.to_owned(),
+        hash: ROOT_SCOPE_HASH.to_owned(),
+        address_key,
+        store_dir,
+        chain: state.chain,
+        pins,
+        checkout_locks: state.checkout_locks,
     };
 
     debug!("Created resolve state as {:?}", resolve_state);
 
-    Ok(resolve_state)
+    resolve_state
 }
@@ -0,0 +1,191 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{eyre, Report};
+use tracing::{debug, instrument, warn};
+
+use super::{
+    git::remote_commit,
+    history::{history_command, HistoryFilter},
+    lockfile::GitPin,
+    process::EntrypointOut,
+    state::{root_git_address, AddressIn, InferContext, ResolvedAddress, StateOptions},
+};
+
+#[derive(Debug, Clone)]
+pub(crate) struct WatchOptions {
+    /// How often the remote is asked what the ref points to.
+    pub(crate) interval: Duration,
+    /// A new commit is only deployed once the ref has pointed to it for this long, so that several pushes in
+    /// quick succession lead to a single deploy.
+    pub(crate) debounce: Duration,
+    /// Minimum time between the start of two deploys.
+    pub(crate) min_interval: Duration,
+    /// Stop watching after this many deploys, instead of watching forever.
+    pub(crate) max_deploys: Option<usize>,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            debounce: Duration::from_secs(10),
+            min_interval: Duration::ZERO,
+            max_deploys: None,
+        }
+    }
+}
+
+/// A deploy started by `watch` because the ref pointed to a new commit.
+#[non_exhaustive]
+pub struct WatchedDeploy {
+    /// The commit the ref pointed to when the deploy was started.
+    pub commit: String,
+    /// None if the deploy failed before the entrypoint could run, see `error`.
+    pub out: Option<EntrypointOut>,
+    pub error: Option<String>,
+}
+
+/// A commit that has not been deployed yet, which is deployed from `ready` on.
+struct Pending {
+    pin: GitPin,
+    ready: Instant,
+}
+
+/// The commit of the most recent successful run of the root address with this URL, ref and state path.
+fn last_deployed(
+    state_options: StateOptions,
+    url: &str,
+    git_ref: &str,
+    state_path: &str,
+) -> Result<Option<String>, Report> {
+    let entries = history_command(Some(state_options), HistoryFilter::default())?;
+
+    Ok(entries
+        .into_iter()
+        .rev()
+        .filter(|e| e.success())
+        .find_map(|e| match e.chain.into_iter().next() {
+            Some(ResolvedAddress::Git {
+                url: u,
+                git_ref: r,
+                state_path: p,
+                commit,
+                ..
+            }) if u == url && r == git_ref && p == state_path => Some(commit),
+            _ => None,
+        }))
+}
+
+/// Checks the ref of the root Git address every `interval` and calls `deploy` whenever it points to a commit
+/// other than the one deployed last. The state options it is called with pin the root address to that
+/// commit, so that exactly the commit that was seen is deployed. When the watcher starts, the last deployed
+/// commit is that of the last successful run in the history, so restarting it does not deploy the same commit
+/// again. A failed deploy is not retried until the ref moves again. Every deploy is passed to `report`, and
+/// the watcher returns once `max_deploys` is reached.
+#[instrument(name = "watch", level = "debug", skip_all)]
+pub(crate) fn watch_command(
+    addr_in: AddressIn,
+    git_infer: bool,
+    state_options: Option<StateOptions>,
+    options: WatchOptions,
+    mut deploy: impl FnMut(AddressIn, StateOptions) -> Result<EntrypointOut, Report>,
+    mut report: impl FnMut(WatchedDeploy),
+) -> Result<(), Report> {
+    let infer_ctx = if git_infer {
+        InferContext::Git
+    } else {
+        InferContext::Cwd
+    };
+    let state_options = state_options.unwrap_or_default();
+    if state_options.offline {
+        return Err(eyre!("Cannot watch a Git address offline!"));
+    }
+    let state_path = match &addr_in {
+        AddressIn::Git(address) => address.state_path.clone().unwrap_or_default(),
+        AddressIn::Local(_) => String::new(),
+    };
+    let address = root_git_address(addr_in.clone(), infer_ctx)?
+        .ok_or_else(|| eyre!("Only a Git address can be watched!"))?;
+
+    let mut deployed: Option<String> = None;
+    let mut first_check = true;
+    let mut pending: Option<Pending> = None;
+    let mut last_deploy: Option<Instant> = None;
+    let mut deploys = 0;
+
+    loop {
+        match remote_commit(&address, &state_options.store_dir) {
+            Ok(pin) => {
+                if first_check {
+                    deployed =
+                        last_deployed(state_options.clone(), &pin.url, &pin.git_ref, &state_path)?;
+                    debug!("Last deployed commit is {:?}", deployed);
+                }
+                if deployed.as_ref() == Some(&pin.commit) {
+                    pending = None;
+                } else if pending.as_ref().is_none_or(|p| p.pin.commit != pin.commit) {
+                    println!("{} now points to {}.", address.git_ref, pin.commit);
+                    // Nothing to debounce when starting, as the ref did not just move
+                    let delay = if first_check {
+                        Duration::ZERO
+                    } else {
+                        options.debounce
+                    };
+                    pending = Some(Pending {
+                        pin,
+                        ready: Instant::now() + delay,
+                    });
+                }
+                first_check = false;
+            }
+            // The remote might be temporarily unreachable, so just try again next time
+            Err(e) => warn!("Failed to check {} for a new commit! {}", address.url, e),
+        }
+
+        let ready = pending.as_ref().is_some_and(|p| {
+            Instant::now() >= p.ready
+                && last_deploy.is_none_or(|t| t.elapsed() >= options.min_interval)
+        });
+        if let Some(Pending { pin, .. }) = pending.take_if(|_| ready) {
+            let commit = pin.commit.clone();
+            println!("Deploying {}...", commit);
+            last_deploy = Some(Instant::now());
+            let pinned_options = StateOptions {
+                root_pin: Some(pin),
+                ..state_options.clone()
+            };
+            let result = deploy(addr_in.clone(), pinned_options);
+            let watched = match result {
+                Ok(out) => {
+                    if !out.exit.success() {
+                        println!("Deploy of {} failed ({}).", commit, out.exit);
+                    }
+                    WatchedDeploy {
+                        commit: commit.clone(),
+                        out: Some(out),
+                        error: None,
+                    }
+                }
+                Err(e) => {
+                    println!("Deploy of {} failed! {}", commit, e);
+                    WatchedDeploy {
+                        commit: commit.clone(),
+                        out: None,
+                        error: Some(e.to_string()),
+                    }
+                }
+            };
+            deployed = Some(commit);
+            report(watched);
+            deploys += 1;
+            if options.max_deploys.is_some_and(|max| deploys >= max) {
+                return Ok(());
+            }
+        }
+
+        thread::sleep(options.interval);
+    }
+}
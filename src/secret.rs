@@ -74,7 +74,8 @@ pub(crate) fn secret_command(state: &State, key: String) -> Result<(), AuthError
             source: e.into(),
         }
     })?;
-    Ok(println!("Set password with store_key {}!", &store_key))
+    println!("Set password with store_key {}!", &store_key);
+    Ok(())
 }
 
 /// Gets secret using a key with format <key>:<repo name>/<deploy path>/<commit sha>. If it cannot find an exact
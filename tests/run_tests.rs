@@ -1,10 +1,20 @@
+use std::{
+    env, fs,
+    process::Command,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
 use camino::Utf8PathBuf;
 use keyring::Entry;
 use test_log::test;
 
 use tidploy::{
-    run_command, secret_command, AddressIn, CommandError, GlobalArguments, LocalAddressIn,
-    RunArguments, SecretArguments,
+    cache_list_command, cache_prune_command, history_command, logs_command, read_store_meta,
+    rollback_command, run_command, secret_command, update_command, watch_command, AddressIn,
+    CommandError, GitAddressIn, GlobalArguments, HistoryArguments, LocalAddressIn, LockWait,
+    LogsArguments, PruneArguments, ResolvedAddress, RestartPolicy, RunArguments, SecretArguments,
+    StoreMeta, SuperviseArguments, WatchArguments, LOCK_FILENAME, META_FILENAME, META_VERSION,
 };
 
 #[test]
@@ -74,6 +84,945 @@ fn test_input() -> Result<(), CommandError> {
     Ok(())
 }
 
+#[test]
+fn test_timeout_retries() -> Result<(), CommandError> {
+    let global_args = GlobalArguments::default();
+    let mut args = RunArguments::default();
+    args.executable = Some("examples/run/example_hang.sh".to_owned());
+    args.timeout = Some(1);
+    args.retries = Some(1);
+
+    let start = Instant::now();
+    let output = run_command(global_args, args)?;
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert!(!output.exit.success());
+
+    assert_eq!(2, output.attempts.len());
+    assert!(output.attempts.iter().all(|a| a.timed_out));
+    assert_eq!("Pulling...\n", output.out);
+
+    Ok(())
+}
+
+#[test]
+fn test_retry_on() -> Result<(), CommandError> {
+    let global_args = GlobalArguments::default();
+    let mut args = RunArguments::default();
+    args.executable = Some("examples/run/example_exit.sh".to_owned());
+    args.retries = Some(2);
+    args.retry_on = vec![3];
+
+    let output = run_command(global_args, args)?;
+    assert_eq!(Some(3), output.exit.code());
+    assert_eq!(3, output.attempts.len());
+
+    let global_args = GlobalArguments::default();
+    let mut args = RunArguments::default();
+    args.executable = Some("examples/run/example_exit.sh".to_owned());
+    args.retries = Some(2);
+    args.retry_on = vec![4];
+
+    let output = run_command(global_args, args)?;
+    assert_eq!(1, output.attempts.len());
+
+    Ok(())
+}
+
+/// Runs the env example through the CLI with `args`, giving the variables it prints to the tidploy process
+/// only. Returns whether it succeeded, and what the entrypoint printed or the error.
+fn run_env_example(args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_tidploy"))
+        .args(["next", "run", "examples/run/example_env.sh"])
+        .args(args)
+        .env("TIDPLOY_TEST_INHERITED", "kept")
+        .env("TIDPLOY_TEST_LEAKED", "leaked")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let printed = stdout
+        .lines()
+        .find(|l| l.ends_with("leaked") || l.ends_with("unset"))
+        .unwrap_or_default()
+        .to_owned();
+
+    if output.status.success() {
+        (true, printed)
+    } else {
+        (false, String::from_utf8(output.stderr).unwrap())
+    }
+}
+
+#[test]
+fn test_clean_env() {
+    assert_eq!((true, "kept leaked".to_owned()), run_env_example(&[]));
+
+    let args = ["--clean-env", "--inherit", "TIDPLOY_TEST_INHERITED"];
+    assert_eq!((true, "kept unset".to_owned()), run_env_example(&args));
+
+    // Inheriting without a clean environment would have no effect
+    let (success, err) = run_env_example(&["--inherit", "TIDPLOY_TEST_INHERITED"]);
+    assert!(!success);
+    assert!(err.contains("clean_env is not set"));
+
+    let args = ["--clean-env=false", "--inherit", "TIDPLOY_TEST_INHERITED"];
+    assert_eq!((true, "kept leaked".to_owned()), run_env_example(&args));
+}
+
+#[test]
+fn test_pty() -> Result<(), CommandError> {
+    let global_args = GlobalArguments::default();
+    let mut args = RunArguments::default();
+    args.executable = Some("examples/run/example_tty.sh".to_owned());
+
+    let output = run_command(global_args, args)?;
+    assert_eq!("Not in a terminal!\n", output.out);
+
+    let global_args = GlobalArguments::default();
+    let mut args = RunArguments::default();
+    args.executable = Some("examples/run/example_tty.sh".to_owned());
+    args.pty = true;
+
+    let output = run_command(global_args, args)?;
+    assert!(output.exit.success());
+    assert_eq!("In a terminal!\r\n", output.out);
+
+    Ok(())
+}
+
+#[test]
+fn test_pty_exit() -> Result<(), CommandError> {
+    let global_args = GlobalArguments::default();
+    let mut args = RunArguments::default();
+    args.executable = Some("examples/run/example_exit.sh".to_owned());
+    args.pty = true;
+
+    let output = run_command(global_args, args)?;
+    assert_eq!(Some(3), output.exit.code());
+
+    Ok(())
+}
+
+#[test]
+fn test_supervise() -> Result<(), CommandError> {
+    let global_args = GlobalArguments::default();
+    let mut args = RunArguments::default();
+    args.executable = Some("examples/run/example_echo.sh".to_owned());
+    args.supervise = Some(SuperviseArguments::new(RestartPolicy::OnFailure));
+
+    let output = run_command(global_args, args)?;
+    assert!(output.exit.success());
+
+    let store_dir = tempfile::tempdir().unwrap();
+    let mut global_args = GlobalArguments::default();
+    global_args.store_dir = Some(Utf8PathBuf::from_path_buf(store_dir.path().to_owned()).unwrap());
+    let args = || {
+        let mut args = RunArguments::default();
+        args.executable = Some("examples/run/example_exit.sh".to_owned());
+        let mut supervise = SuperviseArguments::new(RestartPolicy::Always);
+        supervise.backoff = Some(0);
+        supervise.crash_limit = Some(2);
+        args.supervise = Some(supervise);
+        args
+    };
+
+    let err = run_command(global_args.clone(), args()).err().unwrap();
+    assert!(err.to_string().contains("failed 3 times"));
+    // Every start is recorded, as an entrypoint that is always restarted might never return
+    let entries = history_command(global_args.clone(), HistoryArguments::default())?;
+    assert_eq!(3, entries.len());
+    assert!(entries.iter().all(|e| e.exit_code == Some(3)));
+
+    // Such an entrypoint never finishes, so it cannot become a release
+    let mut args = args();
+    args.release = true;
+    assert!(run_command(global_args.clone(), args).is_err());
+    assert_eq!(
+        3,
+        history_command(global_args, HistoryArguments::default())?.len()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_logs() -> Result<(), CommandError> {
+    let store_dir = tempfile::tempdir().unwrap();
+    let mut global_args = GlobalArguments::default();
+    global_args.store_dir = Some(Utf8PathBuf::from_path_buf(store_dir.path().to_owned()).unwrap());
+
+    let mut args = RunArguments::default();
+    args.executable = Some("examples/run/example_exit.sh".to_owned());
+    args.retries = Some(1);
+    args.log = true;
+    args.log_max_files = Some(1);
+    run_command(global_args.clone(), args)?;
+
+    let log = logs_command(global_args.clone(), LogsArguments::default())?.unwrap();
+    assert_eq!("Failing...\n\n--- attempt 2/2 ---\nFailing...\n", log);
+
+    let mut args = RunArguments::default();
+    args.executable = Some("examples/run/example_echo.sh".to_owned());
+    args.log = true;
+    args.log_max_files = Some(1);
+    run_command(global_args.clone(), args)?;
+
+    let mut logs_args = LogsArguments::default();
+    logs_args.lines = Some(1);
+    let log = logs_command(global_args, logs_args)?.unwrap();
+    assert_eq!("Success!", log);
+
+    // Both runs share an address, so only the latest run is kept
+    let mut address_dirs = fs::read_dir(store_dir.path().join("logs")).unwrap();
+    let address_dir = address_dirs.next().unwrap().unwrap();
+    assert!(address_dirs.next().is_none());
+    assert_eq!(1, fs::read_dir(address_dir.path()).unwrap().count());
+
+    Ok(())
+}
+
+#[test]
+fn test_history() -> Result<(), CommandError> {
+    let store_dir = tempfile::tempdir().unwrap();
+    let mut global_args = GlobalArguments::default();
+    global_args.store_dir = Some(Utf8PathBuf::from_path_buf(store_dir.path().to_owned()).unwrap());
+
+    for executable in ["example_echo.sh", "example_exit.sh"] {
+        let mut args = RunArguments::default();
+        args.executable = Some(format!("examples/run/{}", executable));
+        run_command(global_args.clone(), args)?;
+    }
+
+    let entries = history_command(global_args.clone(), HistoryArguments::default())?;
+    assert_eq!(2, entries.len());
+    assert!(entries[0].success());
+    assert!(entries[1].executable.ends_with("example_exit.sh"));
+    assert!(matches!(
+        entries[1].chain.as_slice(),
+        [ResolvedAddress::Local { .. }]
+    ));
+
+    let mut args = HistoryArguments::default();
+    args.failed = true;
+    let entries = history_command(global_args.clone(), args)?;
+    assert_eq!(1, entries.len());
+    assert_eq!(Some(3), entries[0].exit_code);
+
+    let mut args = HistoryArguments::default();
+    args.since = Some(SystemTime::now() + Duration::from_secs(60));
+    assert!(history_command(global_args.clone(), args)?.is_empty());
+
+    // Runs that fail before the entrypoint is started are recorded too
+    let source = git_source("One!");
+    let mut git_args = git_global_args(&source, &store_dir);
+    if let Some(AddressIn::Git(address)) = &mut git_args.address {
+        address.git_ref = Some("does-not-exist".to_owned());
+    }
+    assert!(run_command(git_args, RunArguments::default()).is_err());
+    let entries = history_command(global_args, HistoryArguments::default())?;
+    assert_eq!(3, entries.len());
+    assert!(entries[2].error.is_some());
+    assert!(entries[2].chain.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_release_rollback() -> Result<(), CommandError> {
+    let source = git_source("One!");
+    let store_dir = tempfile::tempdir().unwrap();
+    let global_args = git_global_args(&source, &store_dir);
+    let release = || {
+        let mut args = RunArguments::default();
+        args.release = true;
+        run_command(global_args.clone(), args)
+    };
+
+    assert!(rollback_command(global_args.clone()).is_err());
+
+    release()?;
+    thread::sleep(Duration::from_millis(5));
+    git_commit(source.path(), "Two!");
+    assert_eq!("Two!\n", release()?.out);
+    thread::sleep(Duration::from_millis(5));
+    // The failing run should not become a release
+    let entrypoint = source.path().join("entrypoint.sh");
+    fs::write(&entrypoint, "#!/bin/sh\nexit 3").unwrap();
+    git(source.path(), &["add", "."]);
+    git(
+        source.path(),
+        &[
+            "-c",
+            "user.name=tidploy",
+            "-c",
+            "user.email=tidploy@example.com",
+            "commit",
+            "-q",
+            "-m",
+            "Broken!",
+        ],
+    );
+    assert!(!release()?.exit.success());
+
+    // Pruning everything leaves the checkouts of the releases, so they can still be rolled back to
+    let mut prune_args = PruneArguments::default();
+    prune_args.keep = Some(0);
+    let removed = cache_prune_command(global_args.clone(), prune_args)?;
+    assert_eq!(1, removed.len());
+    let repos = cache_list_command(global_args.clone())?;
+    assert_eq!(2, repos[0].checkouts.len());
+    assert!(repos[0].checkouts.iter().all(|c| c.release));
+
+    let output = rollback_command(global_args.clone())?;
+    assert_eq!("One!\n", output.out);
+
+    // The first release is now current, so there is nothing left to roll back to
+    assert!(rollback_command(global_args).is_err());
+
+    // A local directory might have changed since it was released, so it cannot be rolled back to
+    let mut global_args = GlobalArguments::default();
+    global_args.store_dir = Some(Utf8PathBuf::from_path_buf(store_dir.path().to_owned()).unwrap());
+    for _ in 0..2 {
+        let mut args = RunArguments::default();
+        args.executable = Some("examples/run/example_echo.sh".to_owned());
+        args.release = true;
+        run_command(global_args.clone(), args)?;
+        thread::sleep(Duration::from_millis(5));
+    }
+    let Err(err) = rollback_command(global_args) else {
+        panic!("Rolled back to a local directory!");
+    };
+    assert!(format!("{:?}", err).contains("local directory"));
+
+    Ok(())
+}
+
+/// Creates a Git repository with a single commit containing an entrypoint that echoes `message`.
+fn git(dir: &std::path::Path, args: &[&str]) {
+    let status = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+/// Commits an entrypoint that echoes `message`.
+fn git_commit(dir: &std::path::Path, message: &str) {
+    let entrypoint = dir.join("entrypoint.sh");
+    fs::write(&entrypoint, format!("#!/bin/sh\necho \"{}\"", message)).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&entrypoint, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    git(dir, &["add", "."]);
+    git(
+        dir,
+        &[
+            "-c",
+            "user.name=tidploy",
+            "-c",
+            "user.email=tidploy@example.com",
+            "commit",
+            "-q",
+            "-m",
+            message,
+        ],
+    );
+}
+
+fn git_source(message: &str) -> tempfile::TempDir {
+    let source = tempfile::tempdir().unwrap();
+    git(source.path(), &["init", "-q"]);
+    git_commit(source.path(), message);
+
+    source
+}
+
+fn git_global_args(source: &tempfile::TempDir, store_dir: &tempfile::TempDir) -> GlobalArguments {
+    let mut global_args = GlobalArguments::default();
+    global_args.address = Some(AddressIn::Git(GitAddressIn {
+        url: Some(source.path().to_str().unwrap().to_owned()),
+        local: true,
+        ..Default::default()
+    }));
+    global_args.store_dir = Some(Utf8PathBuf::from_path_buf(store_dir.path().to_owned()).unwrap());
+
+    global_args
+}
+
+#[test]
+fn test_git_materialize() -> Result<(), CommandError> {
+    let source = git_source("From Git!");
+    let store_dir = tempfile::tempdir().unwrap();
+
+    let output = run_command(
+        git_global_args(&source, &store_dir),
+        RunArguments::default(),
+    )?;
+    assert_eq!("From Git!\n", output.out);
+
+    // Simulate a checkout that was interrupted before it was complete
+    let commit_dirs: Vec<_> = fs::read_dir(store_dir.path().join("c"))
+        .unwrap()
+        .flat_map(|repo| fs::read_dir(repo.unwrap().path()).unwrap())
+        .flat_map(|commit| fs::read_dir(commit.unwrap().path()).unwrap())
+        .map(|paths| paths.unwrap().path())
+        .filter(|p| p.is_dir())
+        .collect();
+    assert_eq!(1, commit_dirs.len());
+    let commit_dir = Utf8PathBuf::from_path_buf(commit_dirs[0].clone()).unwrap();
+    let Some(StoreMeta::Checkout(meta)) = read_store_meta(&commit_dir)? else {
+        panic!("Checkout has no metadata!");
+    };
+    assert_eq!(Some("HEAD"), meta.git_ref.as_deref());
+    assert_eq!(META_VERSION, meta.version);
+    // Checkouts are detached from the repository clone
+    assert!(!commit_dir.join(".git").exists());
+    fs::remove_file(commit_dir.join(META_FILENAME)).unwrap();
+    fs::remove_file(commit_dir.join("entrypoint.sh")).unwrap();
+
+    let output = run_command(
+        git_global_args(&source, &store_dir),
+        RunArguments::default(),
+    )?;
+    assert_eq!("From Git!\n", output.out);
+
+    Ok(())
+}
+
+#[test]
+fn test_offline() -> Result<(), CommandError> {
+    let source = git_source("One!");
+    let store_dir = tempfile::tempdir().unwrap();
+    let output = run_command(
+        git_global_args(&source, &store_dir),
+        RunArguments::default(),
+    )?;
+    assert_eq!("One!\n", output.out);
+
+    git_commit(source.path(), "Two!");
+    let new_commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .current_dir(source.path())
+        .output()
+        .unwrap()
+        .stdout;
+    let new_commit = String::from_utf8(new_commit).unwrap().trim().to_owned();
+
+    // Offline, HEAD is still the commit of the last fetch
+    let mut global_args = git_global_args(&source, &store_dir);
+    global_args.offline = true;
+    let output = run_command(global_args.clone(), RunArguments::default())?;
+    assert_eq!("One!\n", output.out);
+
+    let Some(AddressIn::Git(address)) = &mut global_args.address else {
+        unreachable!()
+    };
+    address.git_ref = Some(new_commit);
+    assert!(run_command(global_args, RunArguments::default()).is_err());
+
+    let output = run_command(
+        git_global_args(&source, &store_dir),
+        RunArguments::default(),
+    )?;
+    assert_eq!("Two!\n", output.out);
+
+    Ok(())
+}
+
+#[test]
+fn test_tag_selectors() -> Result<(), CommandError> {
+    let source = git_source("One!");
+    git(source.path(), &["tag", "v1.0.0"]);
+    git_commit(source.path(), "Two!");
+    git(
+        source.path(),
+        &[
+            "-c",
+            "user.name=tidploy",
+            "-c",
+            "user.email=tidploy@example.com",
+            "tag",
+            "-a",
+            "v1.1.0",
+            "-m",
+            "Two",
+        ],
+    );
+    git_commit(source.path(), "Three!");
+    git(source.path(), &["tag", "v2.0.0"]);
+    git_commit(source.path(), "Four!");
+    git(source.path(), &["tag", "release"]);
+    let store_dir = tempfile::tempdir().unwrap();
+
+    let run_ref = |git_ref: &str, offline: bool| {
+        let mut global_args = git_global_args(&source, &store_dir);
+        global_args.offline = offline;
+        let Some(AddressIn::Git(address)) = &mut global_args.address else {
+            unreachable!()
+        };
+        address.git_ref = Some(git_ref.to_owned());
+        run_command(global_args, RunArguments::default())
+    };
+
+    assert_eq!("Two!\n", run_ref("semver:^1", false)?.out);
+    assert_eq!("One!\n", run_ref("semver:~1.0", false)?.out);
+    assert_eq!("Three!\n", run_ref("latest-tag:v*", false)?.out);
+    assert_eq!("Two!\n", run_ref("semver:^1", true)?.out);
+    assert!(run_ref("semver:^3", false).is_err());
+    assert!(run_ref("semver:not a version", false).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_ref_kinds() -> Result<(), CommandError> {
+    let source = git_source("Tag!");
+    git(
+        source.path(),
+        &[
+            "-c",
+            "user.name=tidploy",
+            "-c",
+            "user.email=tidploy@example.com",
+            "tag",
+            "-a",
+            "release",
+            "-m",
+            "Release",
+        ],
+    );
+    git_commit(source.path(), "Branch!");
+    git(source.path(), &["branch", "release"]);
+    let tag_commit = Command::new("git")
+        .args(["rev-parse", "release^{}"])
+        .current_dir(source.path())
+        .output()
+        .unwrap()
+        .stdout;
+    let tag_commit = String::from_utf8(tag_commit).unwrap().trim().to_owned();
+    let store_dir = tempfile::tempdir().unwrap();
+
+    let run_ref = |git_ref: &str| {
+        let mut global_args = git_global_args(&source, &store_dir);
+        let Some(AddressIn::Git(address)) = &mut global_args.address else {
+            unreachable!()
+        };
+        address.git_ref = Some(git_ref.to_owned());
+        run_command(global_args, RunArguments::default())
+    };
+
+    assert!(run_ref("release").is_err());
+    assert_eq!("Branch!\n", run_ref("branch:release")?.out);
+    assert_eq!("Tag!\n", run_ref("tag:release")?.out);
+    assert_eq!("Branch!\n", run_ref("refs/heads/release")?.out);
+    assert_eq!("Tag!\n", run_ref(&tag_commit[..8])?.out);
+    assert_eq!(
+        "Tag!\n",
+        run_ref(&format!("commit:{}", &tag_commit[..8]))?.out
+    );
+    assert!(run_ref("missing").is_err());
+    assert!(run_ref("commit:0123456789").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_include_paths() -> Result<(), CommandError> {
+    let source = git_source("Root!");
+    fs::create_dir_all(source.path().join("deploy")).unwrap();
+    fs::write(source.path().join("deploy/tidploy.toml"), "").unwrap();
+    fs::create_dir_all(source.path().join("scripts")).unwrap();
+    fs::copy(
+        source.path().join("entrypoint.sh"),
+        source.path().join("scripts/entrypoint.sh"),
+    )
+    .unwrap();
+    git_commit(source.path(), "Scripts!");
+    let store_dir = tempfile::tempdir().unwrap();
+
+    let run_included = |include_paths: Vec<String>| {
+        let mut global_args = git_global_args(&source, &store_dir);
+        let Some(AddressIn::Git(address)) = &mut global_args.address else {
+            unreachable!()
+        };
+        address.state_path = Some("deploy".to_owned());
+        address.include_paths = include_paths;
+        let mut args = RunArguments::default();
+        args.executable = Some("scripts/entrypoint.sh".to_owned());
+        run_command(global_args, args)
+    };
+
+    // Only the state path (and files at the root) are checked out by default
+    assert!(run_included(Vec::new()).is_err());
+    let output = run_included(vec!["scripts".to_owned()])?;
+    assert_eq!("Root!\n", output.out);
+
+    Ok(())
+}
+
+#[test]
+fn test_submodules() -> Result<(), CommandError> {
+    // Git does not allow submodules from local paths by default
+    env::set_var("GIT_CONFIG_COUNT", "1");
+    env::set_var("GIT_CONFIG_KEY_0", "protocol.file.allow");
+    env::set_var("GIT_CONFIG_VALUE_0", "always");
+
+    let sub = git_source("Submodule!");
+    let source = git_source("Root!");
+    fs::create_dir_all(source.path().join("deploy")).unwrap();
+    git(
+        source.path(),
+        &[
+            "submodule",
+            "add",
+            "-q",
+            sub.path().to_str().unwrap(),
+            "deploy/sub",
+        ],
+    );
+    git_commit(source.path(), "Root!");
+    let store_dir = tempfile::tempdir().unwrap();
+
+    let run_submodules = |submodules: bool| {
+        let mut global_args = git_global_args(&source, &store_dir);
+        let Some(AddressIn::Git(address)) = &mut global_args.address else {
+            unreachable!()
+        };
+        address.state_path = Some("deploy".to_owned());
+        address.submodules = submodules;
+        let mut args = RunArguments::default();
+        args.executable = Some("deploy/sub/entrypoint.sh".to_owned());
+        run_command(global_args, args)
+    };
+    // Resolved from the root of the repository, which includes every submodule
+    let run_root = || {
+        let mut global_args = git_global_args(&source, &store_dir);
+        let Some(AddressIn::Git(address)) = &mut global_args.address else {
+            unreachable!()
+        };
+        address.submodules = true;
+        let mut args = RunArguments::default();
+        args.executable = Some("deploy/sub/entrypoint.sh".to_owned());
+        run_command(global_args, args)
+    };
+
+    assert!(run_submodules(false).is_err());
+    let output = run_submodules(true)?;
+    assert_eq!("Submodule!\n", output.out);
+    let checkouts: Vec<_> = fs::read_dir(store_dir.path().join("c"))
+        .unwrap()
+        .flat_map(|repo| fs::read_dir(repo.unwrap().path()).unwrap())
+        .flat_map(|commit| fs::read_dir(commit.unwrap().path()).unwrap())
+        .map(|paths| paths.unwrap().path())
+        .filter(|p| p.join("deploy/sub/entrypoint.sh").exists())
+        .collect();
+    assert_eq!(1, checkouts.len());
+    assert!(!checkouts[0].join("deploy/sub/.git").exists());
+
+    assert_eq!("Submodule!\n", run_root()?.out);
+
+    Ok(())
+}
+
+#[test]
+fn test_lockfile() -> Result<(), CommandError> {
+    let source = git_source("One!");
+    let store_dir = tempfile::tempdir().unwrap();
+    let root = tempfile::tempdir().unwrap();
+    let write_config = |git_ref: &str| {
+        let config = format!(
+            "[state.address]\nurl = {:?}\nlocal = true\ngit_ref = {:?}\n",
+            source.path().to_str().unwrap(),
+            git_ref
+        );
+        fs::write(root.path().join("tidploy.toml"), config).unwrap();
+    };
+    write_config("HEAD");
+
+    let global_args = |locked: bool| {
+        let mut global_args = GlobalArguments::default();
+        global_args.address = Some(AddressIn::Local(LocalAddressIn {
+            resolve_root: Some(root.path().to_str().unwrap().to_owned()),
+            ..Default::default()
+        }));
+        global_args.store_dir =
+            Some(Utf8PathBuf::from_path_buf(store_dir.path().to_owned()).unwrap());
+        global_args.locked = locked;
+        global_args
+    };
+
+    // The lock file is only written by update
+    let output = run_command(global_args(false), RunArguments::default())?;
+    assert_eq!("One!\n", output.out);
+    assert!(!root.path().join(LOCK_FILENAME).exists());
+    assert!(run_command(global_args(true), RunArguments::default()).is_err());
+    update_command(global_args(false))?;
+    let lock = fs::read_to_string(root.path().join(LOCK_FILENAME)).unwrap();
+    assert!(lock.contains("git_ref = \"HEAD\""));
+
+    // The pinned commit keeps being used after the branch moves
+    git_commit(source.path(), "Two!");
+    let output = run_command(global_args(false), RunArguments::default())?;
+    assert_eq!("One!\n", output.out);
+    let output = run_command(global_args(true), RunArguments::default())?;
+    assert_eq!("One!\n", output.out);
+
+    let pins = update_command(global_args(false))?;
+    assert_eq!(1, pins.len());
+    assert_eq!("HEAD", pins[0].git_ref);
+    let output = run_command(global_args(true), RunArguments::default())?;
+    assert_eq!("Two!\n", output.out);
+
+    // A new ref is not pinned yet, so it cannot be used when locked
+    git(source.path(), &["tag", "v1.0.0"]);
+    write_config("v1.0.0");
+    assert!(run_command(global_args(true), RunArguments::default()).is_err());
+    let output = run_command(global_args(false), RunArguments::default())?;
+    assert_eq!("Two!\n", output.out);
+    update_command(global_args(false))?;
+    let lock = fs::read_to_string(root.path().join(LOCK_FILENAME)).unwrap();
+    assert!(lock.contains("git_ref = \"v1.0.0\""));
+    assert!(!lock.contains("git_ref = \"HEAD\""));
+
+    // The commit of a root Git address is pinned in the store
+    let mut root_git_args = git_global_args(&source, &store_dir);
+    assert_eq!(
+        "Two!\n",
+        run_command(root_git_args.clone(), RunArguments::default())?.out
+    );
+    root_git_args.locked = true;
+    assert!(run_command(root_git_args.clone(), RunArguments::default()).is_err());
+    let pins = update_command(root_git_args.clone())?;
+    assert_eq!(1, pins.len());
+    assert_eq!(
+        "Two!\n",
+        run_command(root_git_args.clone(), RunArguments::default())?.out
+    );
+
+    git_commit(source.path(), "Three!");
+    assert_eq!(
+        "Two!\n",
+        run_command(root_git_args.clone(), RunArguments::default())?.out
+    );
+    update_command(root_git_args.clone())?;
+    assert_eq!(
+        "Three!\n",
+        run_command(root_git_args, RunArguments::default())?.out
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_deploy_lock() -> Result<(), CommandError> {
+    let source = git_source("One!");
+    let entrypoint = source.path().join("entrypoint.sh");
+    fs::write(&entrypoint, "#!/bin/sh\nsleep 2\necho \"Slow!\"").unwrap();
+    git(source.path(), &["add", "."]);
+    git(
+        source.path(),
+        &[
+            "-c",
+            "user.name=tidploy",
+            "-c",
+            "user.email=tidploy@example.com",
+            "commit",
+            "-q",
+            "-m",
+            "Slow!",
+        ],
+    );
+    let store_dir = tempfile::tempdir().unwrap();
+    let global_args = git_global_args(&source, &store_dir);
+    let run_args = |wait: LockWait| {
+        let mut args = RunArguments::default();
+        args.lock = Some(wait);
+        args
+    };
+
+    let slow = {
+        let global_args = global_args.clone();
+        thread::spawn(move || run_command(global_args, run_args(LockWait::Wait)))
+    };
+    thread::sleep(Duration::from_millis(1000));
+    // The target is reported as busy before anything is resolved, so the missing ref does not matter
+    let mut busy_args = global_args.clone();
+    if let Some(AddressIn::Git(address)) = &mut busy_args.address {
+        address.git_ref = Some("does-not-exist".to_owned());
+    }
+    let Err(err) = run_command(busy_args, run_args(LockWait::NoWait)) else {
+        panic!("Busy target was deployed!");
+    };
+    assert!(format!("{:?}", err).contains("already being deployed"));
+
+    assert_eq!("Slow!\n", slow.join().unwrap()?.out);
+    assert_eq!(
+        "Slow!\n",
+        run_command(global_args, run_args(LockWait::NoWait))?.out
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_watch() -> Result<(), CommandError> {
+    let source = git_source("One!");
+    let remote = tempfile::tempdir().unwrap();
+    let status = Command::new("git")
+        .args(["clone", "-q", "--bare"])
+        .arg(source.path())
+        .arg(remote.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let push = |message: &str| {
+        git_commit(source.path(), message);
+        git(
+            source.path(),
+            &["push", "-q", remote.path().to_str().unwrap(), "HEAD"],
+        );
+    };
+
+    let store_dir = tempfile::tempdir().unwrap();
+    let mut global_args = GlobalArguments::default();
+    global_args.address = Some(AddressIn::Git(GitAddressIn {
+        url: Some(remote.path().to_str().unwrap().to_owned()),
+        ..Default::default()
+    }));
+    global_args.store_dir = Some(Utf8PathBuf::from_path_buf(store_dir.path().to_owned()).unwrap());
+    let watch = |max_deploys: usize| {
+        let global_args = global_args.clone();
+        thread::spawn(move || {
+            let mut args = WatchArguments::default();
+            args.interval = Some(1);
+            args.debounce = Some(1);
+            args.max_deploys = Some(max_deploys);
+            let mut deploys = Vec::new();
+            watch_command(global_args, RunArguments::default(), args, |d| {
+                deploys.push(d)
+            })
+            .map(|_| deploys)
+        })
+    };
+    let runs = || {
+        history_command(global_args.clone(), HistoryArguments::default())
+            .unwrap()
+            .len()
+    };
+    let wait_for_runs = |n: usize| {
+        let start = Instant::now();
+        while runs() < n {
+            assert!(start.elapsed() < Duration::from_secs(30), "No deploy!");
+            thread::sleep(Duration::from_millis(50));
+        }
+    };
+
+    // The current commit is deployed right away, the next one after it is pushed
+    let watcher = watch(2);
+    wait_for_runs(1);
+    push("Two!");
+    let deploys = watcher.join().unwrap()?;
+    assert_eq!(2, deploys.len());
+    assert_eq!("One!\n", deploys[0].out.as_ref().unwrap().out);
+    assert_eq!("Two!\n", deploys[1].out.as_ref().unwrap().out);
+    assert_ne!(deploys[0].commit, deploys[1].commit);
+    // Exactly the commits that were seen are deployed
+    let entries = history_command(global_args.clone(), HistoryArguments::default())?;
+    let commits: Vec<_> = entries.iter().map(|e| e.commit.clone()).collect();
+    let deployed: Vec<_> = deploys.iter().map(|d| Some(d.commit.clone())).collect();
+    assert_eq!(deployed, commits);
+
+    // A restarted watcher knows from the history that the current commit was already deployed
+    let watcher = watch(1);
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(2, runs());
+    push("Three!");
+    let deploys = watcher.join().unwrap()?;
+    assert_eq!("Three!\n", deploys[0].out.as_ref().unwrap().out);
+    assert_eq!(3, runs());
+
+    Ok(())
+}
+
+#[test]
+fn test_verify_signature() -> Result<(), CommandError> {
+    let root = tempfile::tempdir().unwrap();
+    let key = root.path().join("key");
+    let status = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-C", "tidploy", "-f"])
+        .arg(&key)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let public_key = fs::read_to_string(root.path().join("key.pub")).unwrap();
+    fs::write(
+        root.path().join("allowed_signers"),
+        format!("tidploy@example.com {}", public_key),
+    )
+    .unwrap();
+    let signing = [
+        "-c".to_owned(),
+        "gpg.format=ssh".to_owned(),
+        "-c".to_owned(),
+        format!("user.signingkey={}", key.to_str().unwrap()),
+        "-c".to_owned(),
+        "user.name=tidploy".to_owned(),
+        "-c".to_owned(),
+        "user.email=tidploy@example.com".to_owned(),
+    ];
+    let git_signed = |dir: &std::path::Path, args: &[&str]| {
+        let mut all: Vec<&str> = signing.iter().map(String::as_str).collect();
+        all.extend(args);
+        git(dir, &all);
+    };
+
+    let source = git_source("Unsigned!");
+    git(source.path(), &["tag", "v1.0.0"]);
+    fs::write(
+        source.path().join("entrypoint.sh"),
+        "#!/bin/sh\necho \"Signed!\"",
+    )
+    .unwrap();
+    git_signed(
+        source.path(),
+        &["commit", "-q", "-a", "-S", "-m", "Signed!"],
+    );
+    git_signed(source.path(), &["tag", "-s", "v1.1.0", "-m", "Signed"]);
+
+    let store_dir = tempfile::tempdir().unwrap();
+    let run_verified = |git_ref: &str, verify: &str| {
+        let config = format!(
+            "[state.address]\nurl = {:?}\nlocal = true\ngit_ref = {:?}\nverify = {:?}\nallowed_signers = \"allowed_signers\"\n",
+            source.path().to_str().unwrap(),
+            git_ref,
+            verify
+        );
+        fs::write(root.path().join("tidploy.toml"), config).unwrap();
+        let _ = fs::remove_file(root.path().join(LOCK_FILENAME));
+
+        let mut global_args = GlobalArguments::default();
+        global_args.address = Some(AddressIn::Local(LocalAddressIn {
+            resolve_root: Some(root.path().to_str().unwrap().to_owned()),
+            ..Default::default()
+        }));
+        global_args.store_dir =
+            Some(Utf8PathBuf::from_path_buf(store_dir.path().to_owned()).unwrap());
+        run_command(global_args, RunArguments::default())
+    };
+
+    assert_eq!("Signed!\n", run_verified("HEAD", "commit")?.out);
+    assert_eq!("Signed!\n", run_verified("v1.1.0", "tag")?.out);
+    assert_eq!("Signed!\n", run_verified("semver:^1", "tag")?.out);
+    // A lightweight tag has no signature and the commit it points to is not signed
+    assert!(run_verified("v1.0.0", "tag").is_err());
+    assert!(run_verified("v1.0.0", "commit").is_err());
+    assert!(run_verified("HEAD", "tag").is_err());
+
+    Ok(())
+}
+
 #[test]
 fn test_secret_set() -> Result<(), CommandError> {
     let global_args = GlobalArguments::default();
@@ -141,6 +1090,29 @@ fn test_secret_get() -> Result<(), CommandError> {
     Ok(())
 }
 
+#[test]
+fn test_secret_file() -> Result<(), CommandError> {
+    let service = "tidploy_test_service_file";
+    let entry_key = |key: &str| format!("secret_file::tidploy_root::tidploy_default_hash:{}", key);
+    let _file_entry = TestEntry::new(service, &entry_key("file_key"), "abc");
+    let _env_entry = TestEntry::new(service, &entry_key("env_key"), "def");
+
+    let mut global_args = GlobalArguments::default();
+    global_args.address = Some(AddressIn::Local(LocalAddressIn {
+        resolve_root: Some("examples/config/secret_file".to_owned()),
+        ..Default::default()
+    }));
+    let mut args = RunArguments::default();
+    args.service = Some(service.to_owned());
+
+    // Only the secret delivered as a file needs a name that is also valid for a file
+    let output = run_command(global_args, args)?;
+    assert!(output.exit.success());
+    assert_eq!("abc\ndef\n", output.out);
+
+    Ok(())
+}
+
 #[test]
 fn test_config_address() -> Result<(), CommandError> {
     let mut global_args = GlobalArguments::default();
@@ -188,7 +1160,6 @@ fn test_run_execution_path() -> Result<(), CommandError> {
     let address_local = LocalAddressIn {
         resolve_root: Some("examples/config".to_owned()),
         state_path: Some("run_here".to_owned()),
-        ..Default::default()
     };
     global_args.address = Some(AddressIn::Local(address_local));
 